@@ -0,0 +1,76 @@
+use log::{error, info};
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+};
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+use crate::{resume::RESUME_FILE, Config, Error};
+
+// Zips the output directory, keeping the folder of every video, once the run
+// is done. Clips are compressed already, so files are stored as they are.
+pub fn write_archive(archive: &Path, config: &Config) -> Result<Vec<PathBuf>, Error> {
+    let mut files = Vec::new();
+    collect_files(&config.output_dir, &mut files)?;
+    // The archive may be written inside the output directory it packs
+    let skip = fs::canonicalize(archive).ok();
+    files.retain(|path| {
+        fs::canonicalize(path).ok() != skip
+            && path.file_name().is_some_and(|name| name != RESUME_FILE)
+    });
+    files.sort();
+
+    let temp = archive.with_extension("zip.tmp");
+    let mut zip = ZipWriter::new(File::create(&temp)?);
+    let options = SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Stored)
+        .large_file(true);
+    for path in files.iter() {
+        let name = path
+            .strip_prefix(&config.output_dir)
+            .unwrap_or(path)
+            .components()
+            .map(|part| part.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        zip.start_file(name, options)?;
+        io::copy(&mut File::open(path)?, &mut zip)?;
+    }
+    zip.finish()?;
+    fs::rename(&temp, archive)?;
+    Ok(files)
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), Error> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        match path.is_dir() {
+            true => collect_files(&path, files)?,
+            false => files.push(path),
+        }
+    }
+    Ok(())
+}
+
+// Removes the files that went into the archive, and the folders they leave
+// empty, the output directory itself stays
+pub fn remove_archived(files: &[PathBuf], config: &Config) {
+    for path in files.iter() {
+        if let Err(e) = fs::remove_file(path) {
+            error!("Failed to remove {}: {}", path.display(), e);
+        }
+    }
+    let mut dirs = files
+        .iter()
+        .filter_map(|path| path.parent())
+        .filter(|dir| *dir != config.output_dir)
+        .collect::<Vec<_>>();
+    dirs.sort();
+    dirs.dedup();
+    // Deepest first, a folder only empties once its subfolders are gone
+    for dir in dirs.iter().rev() {
+        let _ = fs::remove_dir(dir);
+    }
+    info!("Removed {} archived file(s)", files.len());
+}
@@ -0,0 +1,100 @@
+use log::{error, info};
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::{progress::format_bytes, Error};
+
+struct CachedFile {
+    path: PathBuf,
+    id: String,
+    size: u64,
+    used: SystemTime,
+}
+
+// Removes cached videos, and their subtitles or partial downloads, that no
+// row of the current input refers to, see listed_videos
+pub fn clean_cache(cache_dir: &Path, videos: &HashSet<String>) -> Result<u64, Error> {
+    let mut freed = 0;
+    for file in cached_files(cache_dir)? {
+        if !videos.contains(&file.id) {
+            freed += remove(&file);
+        }
+    }
+    info!("Freed {} from {}", format_bytes(freed), cache_dir.display());
+    Ok(freed)
+}
+
+// Evicts the least recently used files until the cache fits in max_size
+pub fn evict_cache(cache_dir: &Path, max_size: u64) -> Result<u64, Error> {
+    let mut files = cached_files(cache_dir)?;
+    files.sort_by_key(|file| file.used);
+    let mut total = files.iter().map(|file| file.size).sum::<u64>();
+    let mut freed = 0;
+    for file in files.iter() {
+        if total <= max_size {
+            break;
+        }
+        let removed = remove(file);
+        total -= removed;
+        freed += removed;
+    }
+    if freed > 0 {
+        info!(
+            "Freed {} to keep {} under {}",
+            format_bytes(freed),
+            cache_dir.display(),
+            format_bytes(max_size)
+        );
+    }
+    Ok(freed)
+}
+
+fn remove(file: &CachedFile) -> u64 {
+    match fs::remove_file(&file.path) {
+        Ok(()) => {
+            info!(
+                "Removed {} ({})",
+                file.path.display(),
+                format_bytes(file.size)
+            );
+            file.size
+        }
+        Err(e) => {
+            error!("Failed to remove {}: {}", file.path.display(), e);
+            0
+        }
+    }
+}
+
+// Video ids never contain a dot, so everything before the first one is the id
+// for videos, subtitles and .part files alike. Access times are often not
+// tracked, so the modification time stands in for them.
+fn cached_files(cache_dir: &Path) -> Result<Vec<CachedFile>, Error> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(cache_dir)?.flatten() {
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if !metadata.is_file() {
+            continue;
+        }
+        let name = entry.file_name().to_string_lossy().to_string();
+        let used = metadata
+            .accessed()
+            .into_iter()
+            .chain(metadata.modified())
+            .max()
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        files.push(CachedFile {
+            path: entry.path(),
+            id: name.split('.').next().unwrap_or_default().to_string(),
+            size: metadata.len(),
+            used,
+        });
+    }
+    Ok(files)
+}
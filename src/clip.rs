@@ -0,0 +1,1202 @@
+use log::{debug, error, info, warn};
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, BufReader},
+    process::Command,
+    sync::Semaphore,
+    task::JoinSet,
+};
+
+use crate::{
+    config::{GIF_WARN_SECONDS, VAAPI_DEVICE},
+    format_duration, format_time, time_to_seconds, Config, Error, HwAccel, OutputFormat, Progress,
+    ProgressBar, Video, VideoLink,
+};
+use crate::{
+    events::{emit, Event},
+    partial::track_file,
+    report::ClipResult,
+    resume::{is_finished, mark_finished, FinishedClip},
+};
+
+// Each clip is its own task, so clips of one video run concurrently while the
+// shared semaphore still caps the total number of ffmpeg runs. Clips rejected
+// before they reach ffmpeg, or finished in an earlier run, are returned, the
+// rest come out of the tasks.
+pub fn process_video(
+    video: Video,
+    mut clips: Vec<VideoLink>,
+    config: &Config,
+    limit: &Arc<Semaphore>,
+    tasks: &mut JoinSet<Vec<ClipResult>>,
+    progress: &Progress,
+) -> Vec<ClipResult> {
+    let mut rejected = Vec::new();
+    let name = clip_name(&video, config);
+    rename_collisions(&name, &mut clips, config);
+    if let Some(crop) = config.crop {
+        if let Err(reason) = crop.check(&video) {
+            error!("Skipping video {}: {}", video.title(), reason);
+            return fail_all(&video, &clips, &name, config, &reason);
+        }
+    }
+    // A directory left by an earlier run is reused, anything else that stops
+    // it from being created fails every clip of the video
+    let dir = video_dir(&video.id, &name, config);
+    match fs::create_dir(&dir) {
+        Ok(()) => info!("Directory created for {} clips", video.id),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+        Err(e) => {
+            let reason = format!("Cannot create {}: {}", dir.display(), e);
+            error!("{}", reason);
+            return fail_all(&video, &clips, &name, config, &reason);
+        }
+    }
+    let bar = progress.add_bar(
+        format!("Clipping {}", video.title()),
+        clips.len() as u64,
+        false,
+    );
+    let cleanup = config.cleanup_source.then(|| {
+        Arc::new(SourceCleanup {
+            paths: std::iter::once(video.path.clone())
+                .chain(video.subtitles.clone())
+                .collect(),
+            failed: AtomicBool::new(false),
+        })
+    });
+    let mut pieces = Vec::new();
+    for (mut clip, i) in clips.into_iter().zip(1..) {
+        let i = clip.number.unwrap_or(i);
+        let clip_config = clip.format.map(|format| config.with_format(format));
+        let config = clip_config.as_ref().unwrap_or(config);
+        let title = video.title();
+        let checked = clip
+            .resolve_times(video.duration())
+            .and_then(|_| clamp_to_duration(&mut clip, &video, i))
+            .and_then(|_| check_max_duration(&clip, &video, config))
+            .and_then(|_| check_reverse(&clip, &video, config));
+        if let Err(e) = checked {
+            warn!("Skipping clip #{} for video {}: {}", i, title, e);
+            let output = clip_output_path(i, &clip, &name, config);
+            rejected.push(ClipResult::new(&video, i, &clip, output, None, &Err(e)));
+            bar.inc(1);
+            continue;
+        }
+        pad_clip(&mut clip, &video, config);
+        // The fade out needs to know where a clip running to the end stops
+        if config.fade > 0.0 && clip.end_time.is_none() {
+            clip.end_time = video.duration().map(format_time);
+        }
+        let duration = clip
+            .duration()
+            .or_else(|| Some(video.duration()? - time_to_seconds(&clip.start_time)?))
+            .map(|duration| duration / config.speed * config.loop_count as f64);
+        if config.format == OutputFormat::Gif
+            && duration.is_some_and(|duration| duration > GIF_WARN_SECONDS)
+        {
+            warn!(
+                "Clip #{} for video {} is longer than {} seconds, the gif will be large",
+                i, title, GIF_WARN_SECONDS
+            );
+        }
+        if config.concat {
+            pieces.push((i, clip, duration));
+            continue;
+        }
+        let whole = clip.is_whole() && config.copy_whole();
+        let output = match whole {
+            true => whole_output_path(&clip, &name, &video.path, config),
+            false => clip_output_path(i, &clip, &name, config),
+        };
+        let finished = FinishedClip::new(&clip, &output);
+        if is_finished(&finished) {
+            info!(
+                "Skipping clip #{} for video {}: it was finished in an earlier run",
+                i, title
+            );
+            rejected.push(ClipResult::new(&video, i, &clip, output, duration, &Ok(())));
+            bar.inc(1);
+            continue;
+        }
+        let video = video.clone();
+        let name = name.clone();
+        let cleanup = cleanup.clone();
+        let config = config.clone();
+        let bar = bar.clone();
+        let limit = limit.clone();
+        let progress = progress.clone();
+        tasks.spawn(async move {
+            // The semaphore is never closed, so acquiring only waits
+            let _permit = limit.acquire_owned().await.ok();
+            info!("Clipping clip #{} for video: {}", i, title);
+            let label = format!("Encoding clip #{} of {} (%)", i, title);
+            let clip_bar = progress.add_bar(label, 100, false);
+            // A busy disk or machine can make one ffmpeg run fail, the next
+            // attempt starts over from a fresh file
+            let mut attempt = 1;
+            let mut verified = None;
+            let result = loop {
+                let mut result = match whole {
+                    true => copy_whole(&clip, &name, &video.path, &config).await,
+                    false => clip_video(i, &clip, &name, &video, &config, Some(&clip_bar)).await,
+                };
+                if result.is_ok() && config.loop_count > 1 {
+                    result = loop_clip(&output, &config).await;
+                }
+                if result.is_ok() && config.verify {
+                    let check = verify_clip(&output, duration, whole, &config).await;
+                    verified = Some(check.is_ok());
+                    result = check.map_err(|reason| {
+                        Error::Ffmpeg(format!("clip #{} failed verification: {}", i, reason))
+                    });
+                }
+                match result {
+                    Err(e) if attempt < config.clip_retries && e.is_transient() => {
+                        warn!(
+                            "Clip #{} for video {} failed (attempt {}/{}), retrying: {}",
+                            i, title, attempt, config.clip_retries, e
+                        );
+                    }
+                    result => break result,
+                }
+                let _ = fs::remove_file(&output);
+                tokio::time::sleep(Duration::from_secs(config.retry_delay)).await;
+                attempt += 1;
+            };
+            clip_bar.finish();
+            match &result {
+                Ok(()) => {
+                    info!("Clipped clip #{} for video: {}", i, title);
+                    mark_finished(finished);
+                }
+                Err(e) => error!("Failed to clip clip #{} for video {}: {}", i, title, e),
+            }
+            if let (Some(cleanup), Err(_)) = (&cleanup, &result) {
+                cleanup.failed.store(true, Ordering::Relaxed);
+            }
+            if config.thumbnail && result.is_ok() {
+                save_thumbnail(&output, duration, &config).await;
+            }
+            bar.inc(1);
+            let mut clip_result = ClipResult::new(&video, i, &clip, output, duration, &result);
+            clip_result.verified = verified;
+            emit(Event::ClipDone(clip_result.clone()));
+            vec![clip_result]
+        });
+    }
+    if !pieces.is_empty() {
+        let title = video.title();
+        let config = config.clone();
+        let limit = limit.clone();
+        tasks.spawn(async move {
+            info!("Joining clips for video: {}", title);
+            let result = concat_clips(&pieces, &name, &video, &config, &limit, &bar).await;
+            if let (Some(cleanup), Err(_)) = (&cleanup, &result) {
+                cleanup.failed.store(true, Ordering::Relaxed);
+            }
+            match &result {
+                Ok(()) => info!("Joined clips for video: {}", title),
+                Err(e) => error!("Failed to join clips for video {}: {}", title, e),
+            }
+            let output = highlights_path(&video.id, &name, &config);
+            if config.thumbnail && result.is_ok() {
+                let duration = pieces.iter().map(|(_, _, duration)| *duration).sum();
+                save_thumbnail(&output, duration, &config).await;
+            }
+            let results = pieces
+                .iter()
+                .map(|(i, clip, duration)| {
+                    ClipResult::new(&video, *i, clip, output.clone(), *duration, &result)
+                })
+                .collect::<Vec<ClipResult>>();
+            for clip_result in results.iter() {
+                emit(Event::ClipDone(clip_result.clone()));
+            }
+            results
+        });
+    }
+    rejected
+}
+
+fn fail_all(
+    video: &Video,
+    clips: &[VideoLink],
+    name: &str,
+    config: &Config,
+    reason: &str,
+) -> Vec<ClipResult> {
+    clips
+        .iter()
+        .zip(1..)
+        .map(|(clip, i)| {
+            let i = clip.number.unwrap_or(i);
+            let output = clip_output_path(i, clip, name, config);
+            let error = Err(Error::Input(reason.to_string()));
+            ClipResult::new(video, i, clip, output, None, &error)
+        })
+        .collect()
+}
+
+// Every clip task of a video holds a reference, so the source is removed when
+// the last of them is done
+struct SourceCleanup {
+    paths: Vec<PathBuf>,
+    failed: AtomicBool,
+}
+
+impl Drop for SourceCleanup {
+    fn drop(&mut self) {
+        if self.failed.load(Ordering::Relaxed) {
+            for path in self.paths.iter() {
+                warn!("Keeping {} because a clip failed", path.display());
+            }
+            return;
+        }
+        for path in self.paths.iter() {
+            match fs::remove_file(path) {
+                Ok(()) => info!("Removed {}", path.display()),
+                Err(e) => {
+                    error!("Failed to remove {}: {}", path.display(), e)
+                }
+            }
+        }
+    }
+}
+
+// Pieces are cut one after another in input order, then joined with the
+// concat demuxer. Every piece is re-encoded with the same codecs so the
+// demuxer can copy them into one file.
+async fn concat_clips(
+    pieces: &[(i32, VideoLink, Option<f64>)],
+    name: &str,
+    video: &Video,
+    config: &Config,
+    limit: &Semaphore,
+    bar: &ProgressBar,
+) -> Result<(), Error> {
+    let Some((_, first, _)) = pieces.first() else {
+        return Ok(());
+    };
+    let dir = video_dir(&first.id, name, config);
+    let stem = flat_stem(name, &first.id, config);
+    let list = dir.join(format!("{} [highlights].txt", stem));
+    let mut parts = Vec::new();
+    let mut result = Ok(());
+    for (i, clip, _) in pieces.iter() {
+        let part = dir.join(format!("{} [part {}].{}", stem, i, config.extension()));
+        let _permit = limit.acquire().await.ok();
+        let cut = cut_clip(*i, clip, video, &part, config, None).await;
+        bar.inc(1);
+        if let Err(e) = cut {
+            result = Err(e);
+            break;
+        }
+        parts.push(part);
+    }
+
+    if result.is_ok() {
+        let entries = parts
+            .iter()
+            .filter_map(|part| part.file_name())
+            .map(|file| format!("file '{}'\n", file.to_string_lossy().replace('\'', "'\\''")))
+            .collect::<String>();
+        result = match fs::write(&list, entries) {
+            Ok(()) => {
+                let _permit = limit.acquire().await.ok();
+                join_parts(
+                    &first.id,
+                    &list,
+                    &highlights_path(&first.id, name, config),
+                    config,
+                )
+                .await
+            }
+            Err(e) => Err(e.into()),
+        };
+    }
+
+    for part in parts.iter() {
+        let _ = fs::remove_file(part);
+    }
+    let _ = fs::remove_file(&list);
+    result
+}
+
+async fn join_parts(id: &str, list: &Path, target: &Path, config: &Config) -> Result<(), Error> {
+    let _partial = track_file(target);
+    let output = Command::new(&config.ffmpeg_path)
+        .arg("-hide_banner")
+        .arg("-loglevel")
+        .arg("error")
+        .arg("-f")
+        .arg("concat")
+        .arg("-safe")
+        .arg("0")
+        .arg("-i")
+        .arg(list)
+        .arg("-c")
+        .arg("copy")
+        .arg(target)
+        .output()
+        .await
+        .map_err(|e| Error::Ffmpeg(format!("Failed to execute command: {}", e)))?;
+    if !output.status.success() {
+        return Err(Error::Ffmpeg(format!(
+            "joining clips of video {} exited with {}:\n{}",
+            id,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+// Grabs the middle frame of a finished clip as a jpg next to it, a missing
+// thumbnail is only worth a warning
+async fn save_thumbnail(clip: &Path, duration: Option<f64>, config: &Config) {
+    let target = clip.with_extension("jpg");
+    let _partial = track_file(&target);
+    let output = Command::new(&config.ffmpeg_path)
+        .arg("-hide_banner")
+        .arg("-loglevel")
+        .arg("error")
+        .arg("-y")
+        .arg("-ss")
+        .arg(format_time(duration.unwrap_or(0.0) / 2.0))
+        .arg("-i")
+        .arg(clip)
+        .arg("-frames:v")
+        .arg("1")
+        .arg("-q:v")
+        .arg("2")
+        .arg(&target)
+        .output()
+        .await;
+    match output {
+        Ok(output) if output.status.success() => {}
+        Ok(output) => warn!(
+            "Failed to save a thumbnail of {}: {}",
+            clip.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+        Err(e) => warn!("Failed to save a thumbnail of {}: {}", clip.display(), e),
+    }
+}
+
+// Repeats the finished clip --loop times by stream copying it into a new file,
+// which then takes its place
+async fn loop_clip(clip: &Path, config: &Config) -> Result<(), Error> {
+    let stem = clip.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = clip.extension().unwrap_or_default().to_string_lossy();
+    let looped = clip.with_file_name(format!("{} [looping].{}", stem, extension));
+    let _partial = track_file(&looped);
+    let output = Command::new(&config.ffmpeg_path)
+        .arg("-hide_banner")
+        .arg("-loglevel")
+        .arg("error")
+        .arg("-y")
+        .arg("-stream_loop")
+        .arg((config.loop_count - 1).to_string())
+        .arg("-i")
+        .arg(clip)
+        .arg("-c")
+        .arg("copy")
+        .arg(&looped)
+        .output()
+        .await
+        .map_err(|e| Error::Ffmpeg(format!("Failed to execute command: {}", e)))?;
+    if !output.status.success() {
+        let _ = fs::remove_file(&looped);
+        return Err(Error::Ffmpeg(format!(
+            "looping {} exited with {}:\n{}",
+            clip.display(),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    fs::rename(&looped, clip)?;
+    Ok(())
+}
+
+// Truncated clips are the usual silent failure, so a clip must have streams
+// and run about as long as its range. Stream copies start at the keyframe
+// before the start, so they may run longer.
+async fn verify_clip(
+    clip: &Path,
+    expected: Option<f64>,
+    whole: bool,
+    config: &Config,
+) -> Result<(), String> {
+    let output = Command::new(config.ffprobe_path())
+        .arg("-v")
+        .arg("error")
+        .arg("-show_entries")
+        .arg("format=duration:stream=codec_type")
+        .arg("-of")
+        .arg("json")
+        .arg(clip)
+        .output()
+        .await
+        .map_err(|e| format!("ffprobe could not run: {}", e))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    let probe = serde_json::from_slice::<serde_json::Value>(&output.stdout)
+        .map_err(|e| format!("ffprobe gave no usable output: {}", e))?;
+    let streams = probe["streams"]
+        .as_array()
+        .map_or(0, |streams| streams.len());
+    if streams == 0 {
+        return Err("it has no streams".to_string());
+    }
+    let Some(expected) = expected else {
+        return Ok(());
+    };
+    let actual = probe["format"]["duration"]
+        .as_str()
+        .and_then(|duration| duration.parse::<f64>().ok())
+        .ok_or("ffprobe found no duration")?;
+    let tolerance = VERIFY_TOLERANCE + expected * 0.05;
+    let too_long = !whole && !config.stream_copy() && actual > expected + tolerance;
+    if actual < expected - tolerance || too_long {
+        return Err(format!(
+            "it runs {} instead of {}",
+            format_time(actual),
+            format_time(expected)
+        ));
+    }
+    Ok(())
+}
+
+// Runs after clamp_to_duration, so padding past the end of the video is cut
+// off quietly rather than warned about
+fn pad_clip(clip: &mut VideoLink, video: &Video, config: &Config) {
+    if config.start_buffer > 0.0 {
+        if let Some(start) = time_to_seconds(&clip.start_time) {
+            clip.start_time = format_time((start - config.start_buffer).max(0.0));
+        }
+    }
+    if config.end_buffer > 0.0 {
+        if let Some(end) = clip.end_time.as_deref().and_then(time_to_seconds) {
+            let end = end + config.end_buffer;
+            let end = video.duration().map_or(end, |duration| end.min(duration));
+            clip.end_time = Some(format_time(end));
+        }
+    }
+}
+
+// A guard against mistyped times, whole videos are asked for on purpose
+fn check_max_duration(clip: &VideoLink, video: &Video, config: &Config) -> Result<(), Error> {
+    let Some(max) = config.max_duration.filter(|_| !clip.is_whole()) else {
+        return Ok(());
+    };
+    let duration = clip
+        .duration()
+        .or_else(|| Some(video.duration()? - time_to_seconds(&clip.start_time)?));
+    match duration {
+        Some(duration) if duration > max => Err(Error::Input(format!(
+            "it is {} long, over the --max-duration of {}",
+            format_duration(duration),
+            format_duration(max)
+        ))),
+        _ => Ok(()),
+    }
+}
+
+// reverse and areverse keep every frame of the clip in memory until its end
+fn check_reverse(clip: &VideoLink, video: &Video, config: &Config) -> Result<(), Error> {
+    if !config.reverse {
+        return Ok(());
+    }
+    let duration = clip
+        .duration()
+        .or_else(|| Some(video.duration()? - time_to_seconds(&clip.start_time)?));
+    match duration {
+        Some(duration) if duration > config.max_reverse => Err(Error::Input(format!(
+            "it is {} long, over the --max-reverse of {}",
+            format_duration(duration),
+            format_duration(config.max_reverse)
+        ))),
+        _ => Ok(()),
+    }
+}
+
+fn clamp_to_duration(clip: &mut VideoLink, video: &Video, index: i32) -> Result<(), Error> {
+    let Some(duration) = video.duration() else {
+        return Ok(());
+    };
+    if time_to_seconds(&clip.start_time).is_some_and(|start| start >= duration) {
+        return Err(Error::Input(format!(
+            "start {} is past the end of the video ({})",
+            clip.start_time,
+            format_time(duration)
+        )));
+    }
+    if let Some(end_time) = &clip.end_time {
+        if time_to_seconds(end_time).is_some_and(|end| end > duration) {
+            warn!(
+                "Clip #{} for video {} ends at {}, clamping to the video length {}",
+                index,
+                video.title(),
+                end_time,
+                format_time(duration)
+            );
+            clip.end_time = Some(format_time(duration));
+        }
+    }
+    Ok(())
+}
+
+pub async fn clip_video(
+    index: i32,
+    video: &VideoLink,
+    name: &str,
+    source: &Video,
+    config: &Config,
+    bar: Option<&ProgressBar>,
+) -> Result<(), Error> {
+    let output = clip_output_path(index, video, name, config);
+    cut_clip(index, video, source, &output, config, bar).await
+}
+
+async fn cut_clip(
+    index: i32,
+    video: &VideoLink,
+    source: &Video,
+    output: &Path,
+    config: &Config,
+    bar: Option<&ProgressBar>,
+) -> Result<(), Error> {
+    let (path, subtitles) = (&source.path, source.subtitles.as_deref());
+    let _partial = track_file(output);
+    let mut command = Command::new(&config.ffmpeg_path);
+    // Only errors are logged, so a failure's stderr is just the reason
+    command.arg("-hide_banner").arg("-loglevel").arg("error");
+    // Before -i, ffmpeg seeks to the keyframe before the start, then decodes
+    // and drops frames up to it when re-encoding, so only stream copies snap
+    command.arg("-ss").arg(video.start_time.as_str());
+    if let Some(end_time) = &video.end_time {
+        command.arg("-to").arg(end_time);
+    }
+    if config.hw_encoder() == Some(HwAccel::Vaapi) {
+        command.arg("-vaapi_device").arg(VAAPI_DEVICE);
+    }
+    command.arg("-i").arg(path);
+    // The audio comes from a second, shifted read of the same source
+    if config.audio_offset != 0.0 && config.format == OutputFormat::Mp4 {
+        command
+            .arg("-itsoffset")
+            .arg(config.audio_offset.to_string())
+            .arg("-ss")
+            .arg(video.start_time.as_str());
+        if let Some(end_time) = &video.end_time {
+            command.arg("-to").arg(end_time);
+        }
+        command
+            .arg("-i")
+            .arg(path)
+            .arg("-map")
+            .arg("0:v:0")
+            .arg("-map")
+            .arg("1:a:0?");
+    }
+    let (video_filters, audio_filters) = filters(video, subtitles, config);
+    if config.format == OutputFormat::Gif {
+        // Both passes in one run: generate a palette from the clip, then
+        // encode the clip against it
+        let prefix = video_filters.iter().map(|filter| format!("{},", filter));
+        command.arg("-filter_complex").arg(format!(
+            "{}fps={},scale={}:-1:flags=lanczos,split[a][b];[a]palettegen[p];[b][p]paletteuse",
+            prefix.collect::<String>(),
+            config.gif_fps,
+            config.gif_width
+        ));
+    } else if let Some(codec) = config.format.audio_codec() {
+        command.arg("-vn").arg("-acodec").arg(codec);
+        if !audio_filters.is_empty() {
+            command.arg("-af").arg(audio_filters.join(","));
+        }
+    } else {
+        if !video_filters.is_empty() {
+            command.arg("-vf").arg(video_filters.join(","));
+        }
+        if !audio_filters.is_empty() {
+            command.arg("-af").arg(audio_filters.join(","));
+        }
+        if let Some((video_encoder, audio_encoder)) = config.encoders() {
+            command
+                .arg("-c:v")
+                .arg(video_encoder)
+                .arg("-c:a")
+                .arg(audio_encoder);
+        } else if config.concat {
+            command.arg("-c:v").arg("libx264").arg("-c:a").arg("aac");
+        } else if config.stream_copy() {
+            command
+                .arg("-acodec")
+                .arg("copy")
+                .arg("-vcodec")
+                .arg("copy")
+                .arg("-avoid_negative_ts")
+                .arg("make_zero");
+        }
+    }
+    match config.metadata {
+        true => {
+            for (key, value) in metadata(video, source) {
+                command.arg("-metadata").arg(format!("{}={}", key, value));
+            }
+        }
+        false => {
+            command.arg("-map_metadata").arg("-1");
+        }
+    }
+    // Progress comes as key=value lines on stdout, next to the errors on stderr
+    command.arg("-progress").arg("pipe:1").arg("-nostats");
+    command.arg(output);
+    debug!("Running {:?}", command.as_std());
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| Error::Ffmpeg(format!("Failed to execute command: {}", e)))?;
+    let mut stderr = child.stderr.take();
+    let errors = tokio::spawn(async move {
+        let mut errors = String::new();
+        if let Some(stderr) = stderr.as_mut() {
+            let _ = stderr.read_to_string(&mut errors).await;
+        }
+        errors
+    });
+    let expected = video
+        .duration()
+        .or_else(|| Some(source.duration()? - time_to_seconds(&video.start_time)?))
+        .map(|duration| duration / config.speed);
+    if let Some(stdout) = child.stdout.take() {
+        let mut lines = BufReader::new(stdout).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            if let (Some(bar), Some(expected)) = (bar, expected) {
+                if let Some(percent) = encoded_percent(&line, expected) {
+                    bar.set_position(percent);
+                }
+            }
+        }
+    }
+    let status = child.wait().await?;
+    let errors = errors.await.unwrap_or_default();
+    if !status.success() {
+        return Err(Error::Ffmpeg(format!(
+            "clip #{} of video {} exited with {}:\n{}",
+            index,
+            video.id,
+            status,
+            errors.trim()
+        )));
+    }
+    Ok(())
+}
+
+// out_time_us, in microseconds of output, is the key that tracks how far the
+// encode got
+fn encoded_percent(line: &str, expected: f64) -> Option<u64> {
+    let micros = line
+        .strip_prefix("out_time_us=")?
+        .trim()
+        .parse::<f64>()
+        .ok()?;
+    (expected > 0.0).then(|| (micros / 1e6 / expected * 100.0).clamp(0.0, 99.0) as u64)
+}
+
+// Where the clip came from, as tags most players and file managers show
+fn metadata(video: &VideoLink, source: &Video) -> Vec<(&'static str, String)> {
+    let url = source
+        .data
+        .webpage_url
+        .clone()
+        .unwrap_or_else(|| format!("https://www.youtube.com/watch?v={}", source.id));
+    let end = video.end_time.as_deref().unwrap_or("END");
+    let mut tags = vec![
+        ("title", source.title()),
+        (
+            "comment",
+            format!("Clipped from {} at {} - {}", url, video.start_time, end),
+        ),
+    ];
+    if let Some(uploader) = &source.data.uploader {
+        tags.push(("artist", uploader.clone()));
+    }
+    tags
+}
+
+// Titles can be long, so names are capped well below the 255 byte limit most
+// filesystems have to leave room for the index and extension
+const MAX_NAME_BYTES: usize = 150;
+
+pub fn clip_output_path(index: i32, video: &VideoLink, name: &str, config: &Config) -> PathBuf {
+    video_dir(&video.id, name, config).join(clip_file_name(index, video, name, config))
+}
+
+fn clip_file_name(index: i32, video: &VideoLink, name: &str, config: &Config) -> String {
+    let label = video.name.as_deref().map(sanitize_file_name);
+    let extension = match video.format {
+        Some(format) => config.with_format(format).extension(),
+        None => config.extension(),
+    };
+    match label.filter(|label| !label.is_empty()) {
+        Some(label) => format!("{}.{}", flat_stem(&label, &video.id, config), extension),
+        None => format!(
+            "{} [{}].{}",
+            flat_stem(name, &video.id, config),
+            index,
+            extension
+        ),
+    }
+}
+
+// Clips of a video that would be written to the same file, usually rows
+// sharing a name or a name matching the title, get a number appended from the
+// second one on so nothing is overwritten. Names are compared without case, as
+// Windows and macOS do.
+pub(crate) fn rename_collisions(name: &str, clips: &mut [VideoLink], config: &Config) {
+    let mut taken = HashMap::new();
+    for (clip, i) in clips.iter_mut().zip(1..) {
+        let i = clip.number.unwrap_or(i);
+        let key = |clip: &VideoLink| clip_file_name(i, clip, name, config).to_lowercase();
+        let Some(&first) = taken.get(&key(clip)) else {
+            taken.insert(key(clip), i);
+            continue;
+        };
+        let file_name = clip_file_name(i, clip, name, config);
+        let label = clip
+            .name
+            .clone()
+            .unwrap_or_else(|| format!("{} [{}]", name, i));
+        for n in 2.. {
+            clip.name = Some(format!("{} ({})", label, n));
+            if !taken.contains_key(&key(clip)) {
+                break;
+            }
+        }
+        warn!(
+            "Clip #{} of video {} would overwrite clip #{} as {}, saving it as {} instead, \
+             give the clips distinct names to avoid this",
+            i,
+            clip.id,
+            first,
+            file_name,
+            clip_file_name(i, clip, name, config)
+        );
+        taken.insert(key(clip), i);
+    }
+}
+
+// Seconds a verified clip may be off by, on top of 5% of its length
+const VERIFY_TOLERANCE: f64 = 1.0;
+
+// EBU R128 at -16 LUFS, the usual target for streamed audio
+const LOUDNORM: &str = "loudnorm=I=-16:TP=-1.5:LRA=11";
+
+// Scales the source to fit the frame and centers it over a copy that is
+// scaled to fill the frame, cropped and blurred
+const VERTICAL: &str = "split[bgin][fgin];\
+    [bgin]scale=1080:1920:force_original_aspect_ratio=increase,crop=1080:1920,boxblur=20[bg];\
+    [fgin]scale=1080:1920:force_original_aspect_ratio=decrease[fg];\
+    [bg][fg]overlay=(W-w)/2:(H-h)/2,setsar=1";
+
+// Output timestamps start at zero because -ss comes before -i, so fades are
+// placed relative to the clip rather than the source video
+fn filters(
+    video: &VideoLink,
+    subtitles: Option<&Path>,
+    config: &Config,
+) -> (Vec<String>, Vec<String>) {
+    let mut video_filters = Vec::new();
+    let mut audio_filters = Vec::new();
+    if let Some(crop) = config.crop {
+        video_filters.push(crop.filter());
+    }
+    if let Some(subtitles) = subtitles.filter(|_| config.burn_subs) {
+        // Shift back to source time so the subtitles line up with the clip
+        let start = time_to_seconds(&video.start_time).unwrap_or(0.0);
+        video_filters.push(format!(
+            "setpts=PTS+{}/TB,subtitles={},setpts=PTS-STARTPTS",
+            start,
+            escape_filter_path(subtitles)
+        ));
+    }
+    if config.vertical {
+        video_filters.push(VERTICAL.to_string());
+    }
+    if let Some(watermark) = &config.watermark {
+        // The logo is a single frame, which overlay keeps showing to the end
+        video_filters.push(format!(
+            "null[wmmain];movie={}[wmsrc];\
+            [wmsrc][wmmain]scale2ref=w=oh*a:h=main_h/8[wm][wmbase];\
+            [wmbase][wm]overlay={}",
+            escape_filter_path(watermark),
+            config.watermark_position.position()
+        ));
+    }
+    if let Some(duration) = video.duration().filter(|_| config.fade > 0.0) {
+        let fade = config.fade.min(duration / 2.0);
+        let out = duration - fade;
+        video_filters.push(format!(
+            "fade=t=in:st=0:d={0},fade=t=out:st={1}:d={0}",
+            fade, out
+        ));
+        audio_filters.push(format!(
+            "afade=t=in:st=0:d={0},afade=t=out:st={1}:d={0}",
+            fade, out
+        ));
+    }
+    // Last, so the fades above are placed on the source timeline
+    if config.speed != 1.0 {
+        video_filters.push(format!("setpts=PTS/{}", config.speed));
+        audio_filters.push(atempo(config.speed));
+    }
+    if config.reverse {
+        video_filters.push("reverse".to_string());
+        audio_filters.push("areverse".to_string());
+    }
+    if config.loudnorm {
+        audio_filters.push(LOUDNORM.to_string());
+    }
+    // VAAPI encoders only take frames uploaded to the GPU
+    if config.hw_encoder() == Some(HwAccel::Vaapi) {
+        video_filters.push("format=nv12,hwupload".to_string());
+    }
+    (video_filters, audio_filters)
+}
+
+// atempo only takes factors from 0.5 to 2, so others are reached by chaining
+fn atempo(speed: f64) -> String {
+    let mut speed = speed;
+    let mut filters = Vec::new();
+    while speed > 2.0 {
+        filters.push("atempo=2".to_string());
+        speed /= 2.0;
+    }
+    while speed < 0.5 {
+        filters.push("atempo=0.5".to_string());
+        speed /= 0.5;
+    }
+    filters.push(format!("atempo={}", speed));
+    filters.join(",")
+}
+
+// The subtitles filter takes the path as an option value, where : and '
+// would end it. Forward slashes work on Windows as well.
+fn escape_filter_path(path: &Path) -> String {
+    path.to_string_lossy()
+        .replace('\\', "/")
+        .replace(':', "\\:")
+        .replace('\'', "\\'")
+}
+
+// Keeps the extension yt-dlp downloaded the video with
+pub fn whole_output_path(video: &VideoLink, name: &str, source: &Path, config: &Config) -> PathBuf {
+    let label = video.name.as_deref().map(sanitize_file_name);
+    let file_name = label
+        .filter(|label| !label.is_empty())
+        .unwrap_or(name.to_string());
+    let file_name = flat_stem(&file_name, &video.id, config);
+    let file_name = match source.extension() {
+        Some(extension) => format!("{}.{}", file_name, extension.to_string_lossy()),
+        None => file_name,
+    };
+    video_dir(&video.id, name, config).join(file_name)
+}
+
+async fn copy_whole(
+    video: &VideoLink,
+    name: &str,
+    source: &Path,
+    config: &Config,
+) -> Result<(), Error> {
+    let output = whole_output_path(video, name, source, config);
+    let _partial = track_file(&output);
+    tokio::fs::copy(source, &output).await?;
+    Ok(())
+}
+
+pub fn highlights_path(id: &str, name: &str, config: &Config) -> PathBuf {
+    let stem = flat_stem(name, id, config);
+    video_dir(id, name, config).join(format!("{} [highlights].{}", stem, config.extension()))
+}
+
+// With --flat the clips of every video share the output folder, so their
+// names carry the video id
+fn flat_stem(name: &str, id: &str, config: &Config) -> String {
+    match config.flat && name != id {
+        true => format!("{} [{}]", name, id),
+        false => name.to_string(),
+    }
+}
+
+// Clips of a video go in a folder named after its id, or after the clip name
+// with --title-folders, see title_folders. --flat puts every clip straight in
+// the output folder.
+pub fn video_dir(id: &str, name: &str, config: &Config) -> PathBuf {
+    if config.flat {
+        return long_path(config.output_dir.clone());
+    }
+    if !config.title_folders {
+        return long_path(config.output_dir.join(id));
+    }
+    let folder = config.title_dirs.get(id).map_or(name, String::as_str);
+    long_path(config.output_dir.join(folder))
+}
+
+// Gives each video, in list order, the folder named after it. A name an
+// earlier video already took gets the id appended, so which video keeps the
+// plain name does not depend on which one finishes downloading first.
+pub(crate) fn title_folders(
+    names: impl IntoIterator<Item = (String, String)>,
+) -> HashMap<String, String> {
+    let mut owners = HashMap::new();
+    let mut folders = HashMap::new();
+    for (id, name) in names {
+        let owner = owners.entry(name.clone()).or_insert_with(|| id.clone());
+        let folder = match *owner == id {
+            true => name,
+            false => format!("{} [{}]", name, id),
+        };
+        folders.insert(id, folder);
+    }
+    folders
+}
+
+pub fn clip_name(video: &Video, config: &Config) -> String {
+    title_name(&video.id, video.data.title.as_deref(), config)
+}
+
+pub(crate) fn title_name(id: &str, title: Option<&str>, config: &Config) -> String {
+    let name = match config.id_names {
+        true => String::new(),
+        false => title.map(sanitize_file_name).unwrap_or_default(),
+    };
+    match name.is_empty() {
+        true => id.to_string(),
+        false => name,
+    }
+}
+
+// Replaces characters that are illegal in file names on any of Windows, macOS
+// or Linux, Windows also rejects names ending in a dot or space
+pub fn sanitize_file_name(name: &str) -> String {
+    let mut name = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>();
+    // Titles that only differ past the cut keep apart through the hash
+    if name.len() > MAX_NAME_BYTES {
+        let hash = format!("~{:08x}", fnv_hash(&name));
+        let mut end = MAX_NAME_BYTES - hash.len();
+        while !name.is_char_boundary(end) {
+            end -= 1;
+        }
+        name.truncate(end);
+        name = format!("{}{}", name.trim_end_matches(['.', ' ']), hash);
+    }
+    name.trim_end_matches(['.', ' ']).trim_start().to_string()
+}
+
+// FNV-1a, stable across runs and Rust releases unlike the std hasher
+fn fnv_hash(text: &str) -> u32 {
+    text.bytes().fold(0x811c9dc5, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+// Windows refuses paths over 260 characters unless they carry the \\?\
+// prefix, which needs an absolute path. A folder is prefixed as soon as the
+// longest clip name could push a file in it over the limit.
+#[cfg(windows)]
+fn long_path(dir: PathBuf) -> PathBuf {
+    const MAX_PATH: usize = 260;
+    let Ok(absolute) = std::path::absolute(&dir) else {
+        return dir;
+    };
+    let text = absolute.to_string_lossy();
+    match text.starts_with(r"\\") || text.len() + MAX_NAME_BYTES + 16 < MAX_PATH {
+        true => dir,
+        false => PathBuf::from(format!(r"\\?\{}", text)),
+    }
+}
+
+#[cfg(not(windows))]
+fn long_path(dir: PathBuf) -> PathBuf {
+    dir
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("clip_downloader_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn video() -> Video {
+        let data = serde_json::from_str(r#"{"id": "abcdefghijk", "title": "Title"}"#).unwrap();
+        Video::new(
+            "abcdefghijk".to_string(),
+            PathBuf::from("abcdefghijk.mp4"),
+            data,
+        )
+    }
+
+    fn clip(start: &str, end: &str) -> VideoLink {
+        let mut clip = VideoLink::new("https://youtu.be/abcdefghijk").unwrap();
+        clip.set_start_time(start).unwrap();
+        clip.set_end_time(end).unwrap();
+        clip
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn clips_fail_when_their_folder_cannot_be_created() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = temp_dir("video_dir");
+        let read_only = dir.join("read_only");
+        fs::create_dir(&read_only).unwrap();
+        fs::set_permissions(&read_only, fs::Permissions::from_mode(0o555)).unwrap();
+        // Permissions do not stop root, a file in the way of the folder does
+        let parent = match fs::create_dir(read_only.join("probe")) {
+            Ok(()) => {
+                fs::write(dir.join("file"), "").unwrap();
+                dir.join("file")
+            }
+            Err(_) => read_only.clone(),
+        };
+        let config = Config {
+            output_dir: parent,
+            ..Config::default()
+        };
+        let clips = vec![clip("0:10", "0:20"), clip("0:30", "0:40")];
+        let mut tasks = JoinSet::new();
+        let limit = Arc::new(Semaphore::new(1));
+        let rejected = process_video(
+            video(),
+            clips,
+            &config,
+            &limit,
+            &mut tasks,
+            &Progress::new(true),
+        );
+        assert!(tasks.is_empty());
+        assert_eq!(rejected.len(), 2);
+        for result in rejected.iter() {
+            assert!(!result.success);
+            let error = result.error.as_deref().unwrap_or_default();
+            assert!(error.contains("Cannot create"), "{}", error);
+        }
+        fs::set_permissions(&read_only, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn named(start: &str, end: &str, name: &str) -> VideoLink {
+        VideoLink {
+            name: Some(name.to_string()),
+            ..clip(start, end)
+        }
+    }
+
+    #[test]
+    fn clips_written_to_the_same_file_are_renamed() {
+        let config = Config::default();
+        let mut clips = vec![
+            named("0:10", "0:20", "Intro"),
+            named("0:30", "0:40", "intro"),
+            named("0:50", "1:00", "Intro"),
+            named("1:10", "1:20", "Title [5]"),
+            clip("1:30", "1:40"),
+        ];
+        rename_collisions("Title", &mut clips, &config);
+        let names = clips
+            .iter()
+            .map(|clip| clip.name.as_deref())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                Some("Intro"),
+                Some("intro (2)"),
+                Some("Intro (3)"),
+                Some("Title [5]"),
+                Some("Title [5] (2)")
+            ]
+        );
+
+        // A different format means a different file
+        let mut clips = vec![
+            named("0:10", "0:20", "Intro"),
+            named("0:30", "0:40", "Intro"),
+        ];
+        clips[1].format = Some(OutputFormat::Mp3);
+        rename_collisions("Title", &mut clips, &config);
+        assert_eq!(clips[1].name.as_deref(), Some("Intro"));
+    }
+
+    #[test]
+    fn long_titles_are_cut_and_kept_apart() {
+        let title = "A very long title: ".repeat(30);
+        assert_eq!(title.len(), 570);
+        let first = sanitize_file_name(&format!("{}part 1", title));
+        let second = sanitize_file_name(&format!("{}part 2", title));
+        assert!(first.len() <= MAX_NAME_BYTES, "{}", first.len());
+        assert!(second.len() <= MAX_NAME_BYTES);
+        assert!(first.starts_with("A very long title_ "));
+        assert_ne!(first, second);
+        assert_eq!(first, sanitize_file_name(&format!("{}part 1", title)));
+
+        // The cut never splits a character
+        let wide = sanitize_file_name(&"é".repeat(200));
+        assert!(wide.len() <= MAX_NAME_BYTES);
+        assert!(wide.contains('~'));
+    }
+
+    #[test]
+    fn title_folders_go_to_the_first_video_in_the_list() {
+        let names = [
+            ("bbcdefghijk", "Talk"),
+            ("abcdefghijk", "Talk"),
+            ("cbcdefghijk", "Q&A"),
+        ]
+        .map(|(id, name)| (id.to_string(), name.to_string()));
+        let folders = title_folders(names);
+        assert_eq!(folders["bbcdefghijk"], "Talk");
+        assert_eq!(folders["abcdefghijk"], "Talk [abcdefghijk]");
+        assert_eq!(folders["cbcdefghijk"], "Q&A");
+
+        let config = Config {
+            title_folders: true,
+            title_dirs: folders,
+            ..Config::default()
+        };
+        assert_eq!(
+            video_dir("abcdefghijk", "Talk", &config),
+            long_path(config.output_dir.join("Talk [abcdefghijk]"))
+        );
+    }
+}
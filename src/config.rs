@@ -0,0 +1,1238 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::IsTerminal,
+    path::{Path, PathBuf},
+};
+
+use crate::{input::STDIN_INPUT, time_to_seconds, Error, Video};
+
+const DEFAULT_OUTPUT_DIR: &str = "./video";
+const DEFAULT_CACHE_DIR: &str = "./cache";
+const DEFAULT_DOWNLOAD_THREADS: usize = 2;
+pub(crate) const DEFAULT_FFMPEG_PATH: &str = "ffmpeg";
+const DEFAULT_SHEET: &str = "Sheet1";
+const DEFAULT_RETRIES: u32 = 3;
+const DEFAULT_RETRY_DELAY: u64 = 2;
+const DEFAULT_CLIP_RETRIES: u32 = 2;
+const DEFAULT_MAX_REVERSE: f64 = 60.0;
+const DEFAULT_DOWNLOAD_TIMEOUT: u64 = 60;
+const CONFIG_FILE: &str = "./clip_downloader.json";
+const DEFAULT_GIF_FPS: u32 = 10;
+const DEFAULT_GIF_WIDTH: u32 = 480;
+pub(crate) const GIF_WARN_SECONDS: f64 = 30.0;
+// The first render node, the GPU on most single GPU machines
+pub(crate) const VAAPI_DEVICE: &str = "/dev/dri/renderD128";
+// Speeds outside this range take more than two chained atempo filters
+pub(crate) const SPEED_WARN_MIN: f64 = 0.25;
+pub(crate) const SPEED_WARN_MAX: f64 = 4.0;
+
+const USAGE: &str = "\
+Usage: youtube_clip_downloader [OPTIONS] [INPUT]...
+
+Arguments:
+  [INPUT]...  Clip lists to read, same as giving each with --input. - reads
+              start,end,link lines from stdin, which is also read when no
+              list is given and stdin is piped
+
+Options:
+  -i, --input <FILE>         Clip list to read (.xlsx, .csv, .txt or .json), can be
+                             repeated to merge the clips of several lists
+  -o, --output-dir <DIR>     Directory clips are written to [default: ./video]
+  -c, --cache-dir <DIR>      Directory source videos are cached in [default: ./cache]
+  -t, --threads <N>          Number of clips extracted at once, also --clip-threads.
+                             Every re-encoding ffmpeg already spreads over several
+                             cores, so the default leaves room for that
+                             [default: half the cores]
+      --download-threads <N> Number of videos downloaded at once [default: 2]
+      --ffmpeg-path <PATH>   FFmpeg binary used for clipping and by yt-dlp, looked up
+                             on PATH when not set [default: ffmpeg]
+      --sheet <NAME>         Worksheet to read from .xlsx input [default: Sheet1]
+      --columns <ORDER>      Column order, e.g. link,start,end [default: start,end,link,name]
+  -f, --format <FORMAT>      Clip format: mp4, mp3, m4a, wav or gif, a format column
+                             overrides it per clip [default: mp4]
+      --container <NAME>     Container of video clips: mp4, mkv or webm [default: mp4]
+      --codec <CODEC>        Video codec of video clips: h264, h265, vp9 or av1
+                             [default: h264, vp9 for webm]
+      --hwaccel <API>        Re-encode h264 or h265 on the GPU with nvenc (NVIDIA),
+                             videotoolbox (macOS) or vaapi (Linux), falls back to
+                             software encoding when the encoder does not start
+      --gif-fps <FPS>        Frame rate of gif clips [default: 10]
+      --gif-width <PIXELS>   Width of gif clips [default: 480]
+      --expand-playlists     Treat playlist links as every video in the playlist, each
+                             clipped with the row's times or whole when they are blank
+      --full                 Download whole videos, ignoring the ranges in the input.
+                             Rows with a blank start and end are whole videos too
+      --start-buffer <SECS>  Start every clip this much earlier [default: 0]
+      --end-buffer <SECS>    End every clip this much later [default: 0]
+      --max-duration <TIME>  Skip clips longer than this, in seconds or as mm:ss or
+                             hh:mm:ss, whole videos are still cut [default: unlimited]
+      --by-chapters          Cut one clip per chapter of each video, named after the
+                             chapter, instead of the ranges in the input
+      --segment <TIME>       Split videos whose rows have no range into back to back
+                             clips of this length, the last one running to the end
+      --concat               Join the clips of each video into one highlights file
+      --fade <SECS>          Fade video and audio in and out over this many seconds
+                             at the clip edges, implies re-encoding [default: 0]
+      --loudnorm             Normalize clip audio to -16 LUFS, implies re-encoding
+      --subs <LANG>          Fetch subtitles in this language, e.g. en, with yt-dlp
+      --burn-subs            Draw the --subs subtitles onto the clips, implies re-encoding
+      --crop <W:H[:X:Y]>     Crop clips to a W by H region whose top left corner is at
+                             X, Y, centered when they are left out, implies
+                             re-encoding
+      --watermark <IMAGE>    Overlay a logo in a corner of every clip, scaled to an
+                             eighth of the clip height, implies re-encoding
+      --watermark-position <CORNER>
+                             Corner of the logo: top-left, top-right, bottom-left or
+                             bottom-right [default: bottom-right]
+      --vertical             Fit clips into a 1080x1920 frame for Shorts, over a blurred
+                             copy of the source, implies re-encoding
+      --speed <FACTOR>       Play clips this much faster, e.g. 0.5 for slow motion or
+                             2 for a timelapse, implies re-encoding [default: 1]
+      --reverse              Play clips backward, implies re-encoding
+      --max-reverse <TIME>   Skip clips longer than this with --reverse, which holds
+                             the whole clip in memory [default: 60]
+      --loop <COUNT>         Repeat every clip this many times in one file [default: 1]
+      --audio-offset <SECS>  Delay the audio against the video by this much to fix
+                             drift, negative values make it earlier [default: 0]
+      --overlaps <MODE>      Overlapping ranges of one video: keep them all, merge them
+                             into one clip or keep the first [default: keep]
+      --fast, --copy         Cut without re-encoding, which is much faster but snaps
+                             cuts to the keyframe before the start, so clips may
+                             begin up to a few seconds early. Ignored with --fade,
+                             --loudnorm, --burn-subs, --vertical or --speed, filters
+                             need re-encoding
+      --accurate             Re-encode for frame-exact cuts, the default, overrides
+                             --fast. Seeking still jumps to the nearest keyframe
+                             and decodes from there, so it stays quick
+      --thumbnail            Save the middle frame of every clip as a jpg next to it
+      --verify               Check every clip with ffprobe for streams and the expected
+                             length, a clip that fails counts as failed
+      --no-metadata          Leave out the source title, uploader, link and times that
+                             are otherwise tagged onto cut clips, along with the
+                             tags of the source
+      --title-folders        Put the clips of a video in a folder named after its
+                             title instead of its id, the id is appended when two
+                             videos share a title
+      --flat                 Put every clip straight in the output directory, with
+                             the video id in its name
+      --id-names             Name clips after the video id instead of its title
+      --quality <FORMAT>     yt-dlp format selector for downloads, e.g.
+                             bestvideo[height<=1080]+bestaudio, or a height like 720p,
+                             a quality column overrides it per video
+      --limit-rate <RATE>    Cap download speed in bytes per second, e.g. 500K or 2M
+      --requests-per-minute <N>
+                             Cap how often yt-dlp is started across all downloads and
+                             metadata fetches, to stay clear of YouTube's 429s
+                             [default: unlimited]
+      --cookies <FILE>       Cookies file for age-restricted or members-only videos
+      --cookies-from-browser <BROWSER>
+                             Read cookies from a browser, e.g. firefox or chrome:Profile 1
+      --update-ytdlp         Update yt-dlp before starting, otherwise it is updated
+                             when it has not been checked for a week
+      --proxy <URL>          Proxy for yt-dlp, e.g. http://proxy:8080 or socks5://host:1080
+      --download-timeout <SECS>
+                             Fail a yt-dlp attempt once its connection has received
+                             nothing for this long, and a metadata fetch that takes
+                             longer than this in all. Downloads have no overall limit
+                             since they may run for hours. 0 leaves it to yt-dlp
+                             [default: 60]
+      --retries <N>          Attempts per yt-dlp call before giving up [default: 3]
+      --retry-delay <SECS>   Delay before the first retry, doubled after each [default: 2]
+      --clip-retries <N>     Attempts per clip when ffmpeg fails before the clip counts
+                             as failed, --retry-delay apart [default: 2]
+      --clean-cache          Delete cached videos the input does not refer to and exit
+      --max-cache-size <SIZE>
+                             Evict least recently used cache files after a run until
+                             the cache fits, e.g. 20G or 500M
+      --cleanup-source       Delete each source video once all of its clips are cut.
+                             Kept when a clip failed, so a re-run can retry it
+      --report <FILE>        Where to write the JSON run report [default: <output-dir>/report.json].
+                             A clips.m3u playlist of the clips in input order is
+                             always written to the output directory
+      --list-formats <URL>   Print the formats yt-dlp offers for a video and exit
+      --edl                  Also write clips.edl, an mpv EDL playing the clip ranges
+                             from the cached videos, so it needs them to be kept
+      --archive <FILE>       Pack the output directory, folders included, into this
+                             zip file once the run is done
+      --archive-only         Delete the files that went into the --archive afterwards
+      --probe-only           Check every row and fetch the details of every video
+                             from YouTube without downloading, then list all the
+                             problems found and exit
+      --select               List the clips and pick which ones to process before
+                             starting, e.g. 1-3,5
+      --dry-run              List planned downloads and clips without running them
+  -v, --verbose              Also print debug messages, such as the ffmpeg commands run
+  -q, --quiet                Only print warnings and errors, without progress bars
+      --output-format <FORMAT>
+                             human, or json for one event per line on stdout
+                             (download_started, download_finished, clip_done, error)
+                             with warnings, plans and listings on stderr
+                             [default: human]
+  -h, --help                 Print help
+
+A .json list is an array of objects with url, start, end, name, format and quality
+keys where only the url is required and times may be numbers of seconds.
+
+A start cell may hold the whole range, e.g. 1:20-1:45, with the end left
+blank. Lists of just a range and a link in two columns are read that way.
+A blank start is taken from the t= timestamp of the link, e.g. ?t=1m30s.
+Times may also be given relative to the length of the video: 10% is a tenth of
+the way in and -30 is 30 seconds before the end.
+
+Finished clips are recorded in <output-dir>/.progress.json, so an interrupted
+run skips them when started again. The file is removed once every clip is cut;
+delete it by hand to cut everything again.
+
+The --cookies file must be in the Netscape cookies.txt format, one tab separated
+line per cookie (domain, subdomains, path, secure, expiry, name, value) after a
+\"# Netscape HTTP Cookie File\" header. Browser extensions that export
+cookies.txt produce this format.
+
+Defaults for output_dir, cache_dir, threads, ffmpeg_path and format can be
+set in ./clip_downloader.json, flags given on the command line take precedence.
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OutputFormat {
+    Mp4,
+    Mp3,
+    M4a,
+    Wav,
+    Gif,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Container {
+    Mp4,
+    Mkv,
+    Webm,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoCodec {
+    H264,
+    H265,
+    Vp9,
+    Av1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Crop {
+    pub width: u32,
+    pub height: u32,
+    // None centers the region
+    pub position: Option<(u32, u32)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Corner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HwAccel {
+    Nvenc,
+    VideoToolbox,
+    Vaapi,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverlapMode {
+    Keep,
+    Merge,
+    First,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Columns {
+    pub start: usize,
+    pub end: usize,
+    pub link: usize,
+    pub name: Option<usize>,
+    pub format: Option<usize>,
+    pub quality: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub inputs: Vec<String>,
+    pub sheet: String,
+    pub columns: Option<Columns>,
+    pub output_dir: PathBuf,
+    pub cache_dir: PathBuf,
+    pub threads: usize,
+    pub download_threads: usize,
+    pub ffmpeg_path: PathBuf,
+    pub format: OutputFormat,
+    pub container: Option<Container>,
+    pub codec: Option<VideoCodec>,
+    pub hwaccel: Option<HwAccel>,
+    pub gif_fps: u32,
+    pub gif_width: u32,
+    pub expand_playlists: bool,
+    pub full: bool,
+    pub start_buffer: f64,
+    pub end_buffer: f64,
+    pub max_duration: Option<f64>,
+    pub by_chapters: bool,
+    pub segment: Option<f64>,
+    pub concat: bool,
+    pub fade: f64,
+    pub loudnorm: bool,
+    pub subs: Option<String>,
+    pub burn_subs: bool,
+    pub crop: Option<Crop>,
+    pub watermark: Option<PathBuf>,
+    pub watermark_position: Corner,
+    pub vertical: bool,
+    pub speed: f64,
+    pub reverse: bool,
+    pub max_reverse: f64,
+    pub loop_count: u32,
+    pub audio_offset: f64,
+    pub overlaps: OverlapMode,
+    pub copy: bool,
+    pub accurate: bool,
+    pub thumbnail: bool,
+    pub verify: bool,
+    pub metadata: bool,
+    pub title_folders: bool,
+    // Folder of each video for --title-folders, by id, assigned by run
+    pub title_dirs: HashMap<String, String>,
+    pub flat: bool,
+    pub id_names: bool,
+    pub quality: Option<String>,
+    pub limit_rate: Option<String>,
+    pub requests_per_minute: Option<u32>,
+    pub cookies: Option<PathBuf>,
+    pub cookies_from_browser: Option<String>,
+    pub update_ytdlp: bool,
+    pub proxy: Option<String>,
+    pub download_timeout: u64,
+    pub retries: u32,
+    pub retry_delay: u64,
+    pub clip_retries: u32,
+    pub clean_cache: bool,
+    pub max_cache_size: Option<u64>,
+    pub cleanup_source: bool,
+    pub report: Option<PathBuf>,
+    pub list_formats: Option<String>,
+    pub edl: bool,
+    pub archive: Option<PathBuf>,
+    pub archive_only: bool,
+    pub probe_only: bool,
+    pub select: bool,
+    pub dry_run: bool,
+    pub verbose: bool,
+    pub quiet: bool,
+    pub json_events: bool,
+}
+
+// Defaults read from clip_downloader.json, command line flags take precedence
+#[derive(serde::Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    output_dir: Option<PathBuf>,
+    cache_dir: Option<PathBuf>,
+    threads: Option<usize>,
+    ffmpeg_path: Option<PathBuf>,
+    format: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            inputs: Vec::new(),
+            sheet: DEFAULT_SHEET.to_string(),
+            columns: None,
+            output_dir: PathBuf::from(DEFAULT_OUTPUT_DIR),
+            cache_dir: PathBuf::from(DEFAULT_CACHE_DIR),
+            threads: default_threads(),
+            download_threads: DEFAULT_DOWNLOAD_THREADS,
+            ffmpeg_path: PathBuf::from(DEFAULT_FFMPEG_PATH),
+            format: OutputFormat::Mp4,
+            container: None,
+            codec: None,
+            hwaccel: None,
+            gif_fps: DEFAULT_GIF_FPS,
+            gif_width: DEFAULT_GIF_WIDTH,
+            expand_playlists: false,
+            full: false,
+            start_buffer: 0.0,
+            end_buffer: 0.0,
+            max_duration: None,
+            by_chapters: false,
+            segment: None,
+            concat: false,
+            fade: 0.0,
+            loudnorm: false,
+            subs: None,
+            burn_subs: false,
+            crop: None,
+            watermark: None,
+            watermark_position: Corner::BottomRight,
+            vertical: false,
+            speed: 1.0,
+            reverse: false,
+            max_reverse: DEFAULT_MAX_REVERSE,
+            loop_count: 1,
+            audio_offset: 0.0,
+            overlaps: OverlapMode::Keep,
+            copy: false,
+            accurate: false,
+            thumbnail: false,
+            verify: false,
+            metadata: true,
+            title_folders: false,
+            title_dirs: HashMap::new(),
+            flat: false,
+            id_names: false,
+            quality: None,
+            limit_rate: None,
+            requests_per_minute: None,
+            cookies: None,
+            cookies_from_browser: None,
+            update_ytdlp: false,
+            proxy: None,
+            download_timeout: DEFAULT_DOWNLOAD_TIMEOUT,
+            retries: DEFAULT_RETRIES,
+            retry_delay: DEFAULT_RETRY_DELAY,
+            clip_retries: DEFAULT_CLIP_RETRIES,
+            clean_cache: false,
+            max_cache_size: None,
+            cleanup_source: false,
+            report: None,
+            list_formats: None,
+            edl: false,
+            archive: None,
+            archive_only: false,
+            probe_only: false,
+            select: false,
+            dry_run: false,
+            verbose: false,
+            quiet: false,
+            json_events: false,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_args() -> Result<Self, Error> {
+        Self::parse(std::env::args().skip(1))
+    }
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, Error> {
+        let mut config = Self::default();
+        if let Some(file) = FileConfig::load(Path::new(CONFIG_FILE))? {
+            file.apply(&mut config)?;
+        }
+
+        while let Some(arg) = args.next() {
+            let (flag, inline) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => {
+                    (flag.to_string(), Some(value.to_string()))
+                }
+                _ => (arg.clone(), None),
+            };
+            let mut value = || {
+                inline
+                    .clone()
+                    .or_else(|| args.next())
+                    .ok_or_else(|| Error::Input(format!("Missing value for {}", flag)))
+            };
+
+            match flag.as_str() {
+                "-h" | "--help" => {
+                    print!("{}", USAGE);
+                    std::process::exit(0);
+                }
+                "-i" | "--input" => config.inputs.push(value()?),
+                "--sheet" => config.sheet = value()?,
+                "--columns" => config.columns = Some(parse_value(&flag, &value()?)?),
+                "-o" | "--output-dir" => config.output_dir = PathBuf::from(value()?),
+                "-c" | "--cache-dir" => config.cache_dir = PathBuf::from(value()?),
+                "--download-threads" => config.download_threads = parse_value(&flag, &value()?)?,
+                "-t" | "--threads" | "--clip-threads" => {
+                    config.threads = parse_value(&flag, &value()?)?
+                }
+                "--ffmpeg-path" => config.ffmpeg_path = PathBuf::from(value()?),
+                "-f" | "--format" => config.format = parse_value(&flag, &value()?)?,
+                "--container" => config.container = Some(parse_value(&flag, &value()?)?),
+                "--codec" => config.codec = Some(parse_value(&flag, &value()?)?),
+                "--hwaccel" => config.hwaccel = Some(parse_value(&flag, &value()?)?),
+                "--gif-fps" => config.gif_fps = parse_value(&flag, &value()?)?,
+                "--gif-width" => config.gif_width = parse_value(&flag, &value()?)?,
+                "--expand-playlists" => config.expand_playlists = true,
+                "--full" => config.full = true,
+                "--start-buffer" => config.start_buffer = parse_value(&flag, &value()?)?,
+                "--end-buffer" => config.end_buffer = parse_value(&flag, &value()?)?,
+                "--max-duration" => config.max_duration = Some(parse_duration(&flag, &value()?)?),
+                "--by-chapters" => config.by_chapters = true,
+                "--segment" => config.segment = Some(parse_duration(&flag, &value()?)?),
+                "--concat" => config.concat = true,
+                "--fade" => config.fade = parse_value(&flag, &value()?)?,
+                "--loudnorm" => config.loudnorm = true,
+                "--subs" => config.subs = Some(value()?),
+                "--burn-subs" => config.burn_subs = true,
+                "--crop" => config.crop = Some(parse_value(&flag, &value()?)?),
+                "--watermark" => config.watermark = Some(PathBuf::from(value()?)),
+                "--watermark-position" => {
+                    config.watermark_position = parse_value(&flag, &value()?)?
+                }
+                "--vertical" => config.vertical = true,
+                "--speed" => config.speed = parse_value(&flag, &value()?)?,
+                "--reverse" => config.reverse = true,
+                "--loop" => config.loop_count = parse_value(&flag, &value()?)?,
+                "--audio-offset" => config.audio_offset = parse_value(&flag, &value()?)?,
+                "--max-reverse" => config.max_reverse = parse_duration(&flag, &value()?)?,
+                "--overlaps" => config.overlaps = parse_value(&flag, &value()?)?,
+                "--copy" | "--fast" => config.copy = true,
+                "--accurate" => config.accurate = true,
+                "--thumbnail" => config.thumbnail = true,
+                "--verify" => config.verify = true,
+                "--no-metadata" => config.metadata = false,
+                "--title-folders" => config.title_folders = true,
+                "--flat" => config.flat = true,
+                "--id-names" => config.id_names = true,
+                "--quality" => config.quality = Some(parse_quality(&value()?)),
+                "--limit-rate" => config.limit_rate = Some(parse_rate(&flag, &value()?)?),
+                "--requests-per-minute" => {
+                    config.requests_per_minute = Some(parse_value(&flag, &value()?)?)
+                }
+                "--cookies" => config.cookies = Some(PathBuf::from(value()?)),
+                "--cookies-from-browser" => config.cookies_from_browser = Some(value()?),
+                "--update-ytdlp" => config.update_ytdlp = true,
+                "--proxy" => config.proxy = Some(value()?),
+                "--download-timeout" => config.download_timeout = parse_value(&flag, &value()?)?,
+                "--retries" => config.retries = parse_value(&flag, &value()?)?,
+                "--retry-delay" => config.retry_delay = parse_value(&flag, &value()?)?,
+                "--clip-retries" => config.clip_retries = parse_value(&flag, &value()?)?,
+                "--clean-cache" => config.clean_cache = true,
+                "--max-cache-size" => config.max_cache_size = Some(parse_size(&flag, &value()?)?),
+                "--cleanup-source" | "--no-cache" => config.cleanup_source = true,
+                "--report" => config.report = Some(PathBuf::from(value()?)),
+                "--list-formats" => config.list_formats = Some(value()?),
+                "--edl" => config.edl = true,
+                "--archive" => config.archive = Some(PathBuf::from(value()?)),
+                "--archive-only" => config.archive_only = true,
+                "--probe-only" => config.probe_only = true,
+                "--select" => config.select = true,
+                "--dry-run" => config.dry_run = true,
+                "-v" | "--verbose" => config.verbose = true,
+                "-q" | "--quiet" => config.quiet = true,
+                "--output-format" => config.json_events = parse_output_format(&value()?)?,
+                "-" => config.inputs.push(arg),
+                _ if flag.starts_with('-') => {
+                    return Err(Error::Input(format!(
+                        "Unknown argument {}, see --help",
+                        flag
+                    )))
+                }
+                _ => config.inputs.push(arg),
+            }
+        }
+
+        if config.inputs.is_empty()
+            && config.list_formats.is_none()
+            && !std::io::stdin().is_terminal()
+        {
+            config.inputs.push(STDIN_INPUT.to_string());
+        }
+        if config.inputs.is_empty() && config.list_formats.is_none() {
+            return Err(Error::Input(
+                "No input file provided, see --help".to_string(),
+            ));
+        }
+        if config.threads == 0 {
+            return Err(Error::Input("--threads must be at least 1".to_string()));
+        }
+        if config.download_threads == 0 {
+            return Err(Error::Input(
+                "--download-threads must be at least 1".to_string(),
+            ));
+        }
+        if let Some(cookies) = config.cookies.as_ref().filter(|path| !path.is_file()) {
+            return Err(Error::Input(format!(
+                "Cookies file {} does not exist",
+                cookies.display()
+            )));
+        }
+        if config.concat && config.format == OutputFormat::Gif {
+            return Err(Error::Input(
+                "--concat does not support gif clips".to_string(),
+            ));
+        }
+        for (flag, buffer) in [
+            ("--start-buffer", config.start_buffer),
+            ("--end-buffer", config.end_buffer),
+        ] {
+            if !buffer.is_finite() || buffer < 0.0 {
+                return Err(Error::Input(format!(
+                    "{} must be a non-negative number of seconds",
+                    flag
+                )));
+            }
+        }
+        if !config.fade.is_finite() || config.fade < 0.0 {
+            return Err(Error::Input(
+                "--fade must be a non-negative number of seconds".to_string(),
+            ));
+        }
+        if let Some(watermark) = config.watermark.as_ref().filter(|path| !path.is_file()) {
+            return Err(Error::Input(format!(
+                "Watermark {} does not exist",
+                watermark.display()
+            )));
+        }
+        if !config.speed.is_finite() || config.speed <= 0.0 {
+            return Err(Error::Input(
+                "--speed must be a positive factor".to_string(),
+            ));
+        }
+        if config.burn_subs && config.subs.is_none() {
+            return Err(Error::Input("--burn-subs needs --subs <LANG>".to_string()));
+        }
+        for (flag, set) in [
+            ("--crop", config.crop.is_some()),
+            ("--watermark", config.watermark.is_some()),
+            ("--vertical", config.vertical),
+            ("--thumbnail", config.thumbnail),
+        ] {
+            if set && config.format.audio_codec().is_some() {
+                return Err(Error::Input(format!(
+                    "{} only applies to video clips",
+                    flag
+                )));
+            }
+        }
+        config.check_codecs()?;
+        if config.select && config.inputs.iter().any(|input| input == STDIN_INPUT) {
+            return Err(Error::Input(
+                "--select reads the selection from stdin, so the list must come from a file"
+                    .to_string(),
+            ));
+        }
+        if !config.audio_offset.is_finite() {
+            return Err(Error::Input(
+                "--audio-offset must be a number of seconds".to_string(),
+            ));
+        }
+        if config.audio_offset != 0.0 && config.format != OutputFormat::Mp4 {
+            return Err(Error::Input(
+                "--audio-offset only applies to video clips with sound".to_string(),
+            ));
+        }
+        if config.loop_count == 0 {
+            return Err(Error::Input("--loop must be at least 1".to_string()));
+        }
+        if config.loop_count > 1 && config.concat {
+            return Err(Error::Input(
+                "--loop repeats single clips and does not apply to --concat".to_string(),
+            ));
+        }
+        if config.by_chapters && config.segment.is_some() {
+            return Err(Error::Input(
+                "--by-chapters and --segment cannot be used together".to_string(),
+            ));
+        }
+        if config.flat && config.title_folders {
+            return Err(Error::Input(
+                "--flat and --title-folders cannot be used together".to_string(),
+            ));
+        }
+        if config.archive_only && config.archive.is_none() {
+            return Err(Error::Input(
+                "--archive-only needs --archive <FILE>".to_string(),
+            ));
+        }
+        if config.edl && config.cleanup_source {
+            return Err(Error::Input(
+                "--edl plays the cached videos that --cleanup-source removes".to_string(),
+            ));
+        }
+        if config.retries == 0 {
+            return Err(Error::Input("--retries must be at least 1".to_string()));
+        }
+        if config.requests_per_minute == Some(0) {
+            return Err(Error::Input(
+                "--requests-per-minute must be at least 1".to_string(),
+            ));
+        }
+        if config.clip_retries == 0 {
+            return Err(Error::Input(
+                "--clip-retries must be at least 1".to_string(),
+            ));
+        }
+        Ok(config)
+    }
+    // The codec, container and hardware encoder flags must fit together
+    fn check_codecs(&self) -> Result<(), Error> {
+        if (self.container.is_some() || self.codec.is_some() || self.hwaccel.is_some())
+            && self.format != OutputFormat::Mp4
+        {
+            return Err(Error::Input(
+                "--container, --codec and --hwaccel only apply to video clips".to_string(),
+            ));
+        }
+        // The source is often h264 and aac, which WebM cannot hold
+        if self.copy && self.container == Some(Container::Webm) {
+            return Err(Error::Input(
+                "--copy keeps the codecs of the source, which may not fit in WebM, \
+             use --container mkv"
+                    .to_string(),
+            ));
+        }
+        if let Some(hwaccel) = self.hwaccel {
+            let codec = self.codec.unwrap_or(match self.container {
+                Some(Container::Webm) => VideoCodec::Vp9,
+                _ => VideoCodec::H264,
+            });
+            if hwaccel.encoder(codec).is_none() {
+                return Err(Error::Input(format!(
+                    "--hwaccel {:?} cannot encode {:?}, use h264 or h265",
+                    hwaccel, codec
+                )));
+            }
+        }
+        if let Some(codec) = self.codec {
+            let container = self.container.unwrap_or(Container::Mp4);
+            if !container.supports(codec) {
+                return Err(Error::Input(format!(
+                    "{:?} video cannot be stored in {}",
+                    codec,
+                    container.extension()
+                )));
+            }
+        }
+        Ok(())
+    }
+    // Settings for a clip that picked its own format, options that only apply
+    // to another format are dropped
+    pub fn with_format(&self, format: OutputFormat) -> Self {
+        let mut config = self.clone();
+        config.format = format;
+        if format != OutputFormat::Mp4 {
+            config.container = None;
+            config.codec = None;
+            config.hwaccel = None;
+        }
+        if format.audio_codec().is_some() {
+            config.crop = None;
+            config.watermark = None;
+            config.vertical = false;
+            config.thumbnail = false;
+        }
+        config
+    }
+    // For a video with its own quality in the input
+    pub fn with_quality(&self, quality: &str) -> Self {
+        Self {
+            quality: Some(quality.to_string()),
+            ..self.clone()
+        }
+    }
+    pub fn log_level(&self) -> log::LevelFilter {
+        match (self.quiet, self.verbose) {
+            (true, _) => log::LevelFilter::Warn,
+            (false, true) => log::LevelFilter::Debug,
+            (false, false) => log::LevelFilter::Info,
+        }
+    }
+    // With -ss before -i, stream copy starts at the keyframe before the
+    // requested time, so --accurate falls back to re-encoding. Filters need
+    // decoded frames, so they rule out copying too.
+    // Whole videos are copied out of the cache as they are unless the format
+    // or a filter needs ffmpeg
+    pub fn copy_whole(&self) -> bool {
+        self.format == OutputFormat::Mp4
+            && self.container.is_none()
+            && self.codec.is_none()
+            && !self.has_filters()
+    }
+    pub fn stream_copy(&self) -> bool {
+        self.copy && !self.accurate && self.codec.is_none() && !self.has_filters()
+    }
+    // ffprobe ships with ffmpeg, so it is looked for next to it
+    pub fn ffprobe_path(&self) -> PathBuf {
+        let name = self
+            .ffmpeg_path
+            .file_name()
+            .map(|name| name.to_string_lossy().replacen("ffmpeg", "ffprobe", 1))
+            .unwrap_or_else(|| "ffprobe".to_string());
+        self.ffmpeg_path.with_file_name(name)
+    }
+    pub fn has_filters(&self) -> bool {
+        self.fade > 0.0
+            || self.loudnorm
+            || self.burn_subs
+            || self.crop.is_some()
+            || self.watermark.is_some()
+            || self.vertical
+            || self.speed != 1.0
+            || self.reverse
+    }
+    pub fn extension(&self) -> &'static str {
+        match self.format {
+            OutputFormat::Mp4 => self.container.unwrap_or(Container::Mp4).extension(),
+            format => format.extension(),
+        }
+    }
+    // Video and audio encoders when they have to be given explicitly, ffmpeg
+    // picks h264 and aac for mp4 by itself but vorbis or ac3 audio for mkv.
+    // None as well when the streams are copied.
+    pub fn encoders(&self) -> Option<(&'static str, &'static str)> {
+        let container = self.container.unwrap_or(Container::Mp4);
+        if self.stream_copy() {
+            return None;
+        }
+        if let Some(hwaccel) = self.hw_encoder() {
+            let encoder = hwaccel.encoder(self.codec.unwrap_or(VideoCodec::H264))?;
+            return Some((encoder, "aac"));
+        }
+        let codec = match (self.codec, container) {
+            (Some(codec), _) => codec,
+            (None, Container::Webm) => VideoCodec::Vp9,
+            (None, Container::Mkv) => VideoCodec::H264,
+            (None, Container::Mp4) => return None,
+        };
+        let audio = match container {
+            Container::Webm => "libopus",
+            _ => "aac",
+        };
+        Some((codec.encoder(), audio))
+    }
+    // The GPU only encodes when the clip is re-encoded to video at all
+    pub fn hw_encoder(&self) -> Option<HwAccel> {
+        self.hwaccel
+            .filter(|_| self.format == OutputFormat::Mp4 && !self.stream_copy())
+    }
+}
+
+impl Default for Columns {
+    fn default() -> Self {
+        Self {
+            start: 0,
+            end: 1,
+            link: 2,
+            name: Some(3),
+            format: None,
+            quality: None,
+        }
+    }
+}
+
+impl Columns {
+    pub fn from_header(row: &[String]) -> Option<Self> {
+        let find = |names: &[&str]| {
+            row.iter()
+                .position(|cell| names.contains(&cell.trim().to_lowercase().as_str()))
+        };
+        Some(Self {
+            start: find(&["start", "start time", "from"])?,
+            end: find(&["end", "end time", "to"])?,
+            link: find(&["link", "url", "video"])?,
+            name: find(&["name", "label", "clip"]),
+            format: find(&["format"]),
+            quality: find(&["quality"]),
+        })
+    }
+}
+
+// Names other than start, end, link, name, format and quality mark columns
+// that are ignored
+impl std::str::FromStr for Columns {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let names = s
+            .split(',')
+            .map(|name| name.trim().to_lowercase())
+            .collect::<Vec<String>>();
+        let find = |name: &str| {
+            names
+                .iter()
+                .position(|n| n == name)
+                .ok_or_else(|| format!("Missing {} column", name))
+        };
+        Ok(Self {
+            start: find("start")?,
+            end: find("end")?,
+            link: find("link")?,
+            name: names.iter().position(|n| n == "name"),
+            format: names.iter().position(|n| n == "format"),
+            quality: names.iter().position(|n| n == "quality"),
+        })
+    }
+}
+
+impl OutputFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Mp4 => "mp4",
+            Self::Mp3 => "mp3",
+            Self::M4a => "m4a",
+            Self::Wav => "wav",
+            Self::Gif => "gif",
+        }
+    }
+    pub fn audio_codec(&self) -> Option<&'static str> {
+        match self {
+            Self::Mp4 | Self::Gif => None,
+            Self::Mp3 => Some("libmp3lame"),
+            Self::M4a => Some("aac"),
+            Self::Wav => Some("pcm_s16le"),
+        }
+    }
+}
+
+impl Container {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Mp4 => "mp4",
+            Self::Mkv => "mkv",
+            Self::Webm => "webm",
+        }
+    }
+    // WebM is limited to the royalty free codecs
+    pub fn supports(&self, codec: VideoCodec) -> bool {
+        match self {
+            Self::Mp4 => codec != VideoCodec::Vp9,
+            Self::Mkv => true,
+            Self::Webm => matches!(codec, VideoCodec::Vp9 | VideoCodec::Av1),
+        }
+    }
+}
+
+impl VideoCodec {
+    pub fn encoder(&self) -> &'static str {
+        match self {
+            Self::H264 => "libx264",
+            Self::H265 => "libx265",
+            Self::Vp9 => "libvpx-vp9",
+            Self::Av1 => "libaom-av1",
+        }
+    }
+}
+
+impl Crop {
+    pub fn filter(&self) -> String {
+        match self.position {
+            Some((x, y)) => format!("crop={}:{}:{}:{}", self.width, self.height, x, y),
+            None => format!("crop={}:{}", self.width, self.height),
+        }
+    }
+    // Only checked when yt-dlp reported the resolution
+    pub fn check(&self, video: &Video) -> Result<(), String> {
+        let (Some(width), Some(height)) = (video.data.width, video.data.height) else {
+            return Ok(());
+        };
+        let (x, y) = self.position.unwrap_or((0, 0));
+        // In f64, the sum of two u32 from the command line can overflow
+        if self.width as f64 + x as f64 > width || self.height as f64 + y as f64 > height {
+            return Err(format!(
+                "--crop {} does not fit in its {}x{} frame",
+                self.filter().trim_start_matches("crop="),
+                width,
+                height
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for Crop {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let numbers = s
+            .split(':')
+            .map(|number| number.trim().parse::<u32>())
+            .collect::<Result<Vec<u32>, _>>()
+            .map_err(|_| format!("Invalid crop {}, expected W:H or W:H:X:Y", s))?;
+        let position = match numbers[..] {
+            [_, _] => None,
+            [_, _, x, y] => Some((x, y)),
+            _ => return Err(format!("Invalid crop {}, expected W:H or W:H:X:Y", s)),
+        };
+        if numbers[0] == 0 || numbers[1] == 0 {
+            return Err(format!("Invalid crop {}, the region is empty", s));
+        }
+        Ok(Self {
+            width: numbers[0],
+            height: numbers[1],
+            position,
+        })
+    }
+}
+
+impl Corner {
+    // overlay position, a fortieth of the frame width away from the edges
+    pub fn position(&self) -> &'static str {
+        match self {
+            Self::TopLeft => "W/40:W/40",
+            Self::TopRight => "W-w-W/40:W/40",
+            Self::BottomLeft => "W/40:H-h-W/40",
+            Self::BottomRight => "W-w-W/40:H-h-W/40",
+        }
+    }
+}
+
+impl std::str::FromStr for Corner {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "top-left" => Ok(Self::TopLeft),
+            "top-right" => Ok(Self::TopRight),
+            "bottom-left" => Ok(Self::BottomLeft),
+            "bottom-right" => Ok(Self::BottomRight),
+            _ => Err(format!("Unknown corner {}", s)),
+        }
+    }
+}
+
+impl HwAccel {
+    pub fn encoder(&self, codec: VideoCodec) -> Option<&'static str> {
+        match (self, codec) {
+            (Self::Nvenc, VideoCodec::H264) => Some("h264_nvenc"),
+            (Self::Nvenc, VideoCodec::H265) => Some("hevc_nvenc"),
+            (Self::VideoToolbox, VideoCodec::H264) => Some("h264_videotoolbox"),
+            (Self::VideoToolbox, VideoCodec::H265) => Some("hevc_videotoolbox"),
+            (Self::Vaapi, VideoCodec::H264) => Some("h264_vaapi"),
+            (Self::Vaapi, VideoCodec::H265) => Some("hevc_vaapi"),
+            _ => None,
+        }
+    }
+}
+
+impl std::str::FromStr for HwAccel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "nvenc" | "cuda" => Ok(Self::Nvenc),
+            "videotoolbox" => Ok(Self::VideoToolbox),
+            "vaapi" => Ok(Self::Vaapi),
+            _ => Err(format!("Unknown hardware encoder API {}", s)),
+        }
+    }
+}
+
+impl std::str::FromStr for Container {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "mp4" => Ok(Self::Mp4),
+            "mkv" => Ok(Self::Mkv),
+            "webm" => Ok(Self::Webm),
+            _ => Err(format!("Unknown container {}", s)),
+        }
+    }
+}
+
+impl std::str::FromStr for VideoCodec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "h264" | "avc" => Ok(Self::H264),
+            "h265" | "hevc" => Ok(Self::H265),
+            "vp9" => Ok(Self::Vp9),
+            "av1" => Ok(Self::Av1),
+            _ => Err(format!("Unknown codec {}", s)),
+        }
+    }
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "mp4" => Ok(Self::Mp4),
+            "mp3" => Ok(Self::Mp3),
+            "m4a" => Ok(Self::M4a),
+            "wav" => Ok(Self::Wav),
+            "gif" => Ok(Self::Gif),
+            _ => Err(format!("Unknown format {}", s)),
+        }
+    }
+}
+
+impl std::str::FromStr for OverlapMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "keep" => Ok(Self::Keep),
+            "merge" => Ok(Self::Merge),
+            "first" => Ok(Self::First),
+            _ => Err(format!("Unknown overlap mode {}", s)),
+        }
+    }
+}
+
+impl FileConfig {
+    fn load(path: &Path) -> Result<Option<Self>, Error> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        serde_json::from_str(&content)
+            .map(Some)
+            .map_err(|e| Error::Input(format!("Invalid {}: {}", path.display(), e)))
+    }
+    fn apply(self, config: &mut Config) -> Result<(), Error> {
+        if let Some(output_dir) = self.output_dir {
+            config.output_dir = output_dir;
+        }
+        if let Some(cache_dir) = self.cache_dir {
+            config.cache_dir = cache_dir;
+        }
+        if let Some(threads) = self.threads {
+            config.threads = threads;
+        }
+        if let Some(ffmpeg_path) = self.ffmpeg_path {
+            config.ffmpeg_path = ffmpeg_path;
+        }
+        if let Some(format) = self.format {
+            config.format = parse_value("format", &format)?;
+        }
+        Ok(())
+    }
+}
+
+// Stream copies are cheap, but re-encodes each use most of the cores, so half
+// of them keeps a few ffmpeg jobs busy without thrashing
+fn default_threads() -> usize {
+    std::thread::available_parallelism()
+        .map(|cores| (cores.get() / 2).max(1))
+        .unwrap_or(4)
+}
+
+fn parse_value<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, Error> {
+    value
+        .parse()
+        .map_err(|_| Error::Input(format!("Invalid value \"{}\" for {}", value, flag)))
+}
+
+// A height such as 720p caps the resolution, anything else is handed to
+// yt-dlp as a format selector. A bare number is a format id, e.g. 18 or 22.
+pub(crate) fn parse_quality(value: &str) -> String {
+    match value.strip_suffix('p').map(str::parse::<u32>) {
+        Some(Ok(height)) => format!("bestvideo[height<={0}]+bestaudio/best[height<={0}]", height),
+        _ => value.to_string(),
+    }
+}
+
+fn parse_output_format(value: &str) -> Result<bool, Error> {
+    match value.to_lowercase().as_str() {
+        "human" => Ok(false),
+        "json" => Ok(true),
+        _ => Err(Error::Input(format!(
+            "Unknown output format {}, expected human or json",
+            value
+        ))),
+    }
+}
+
+fn parse_duration(flag: &str, value: &str) -> Result<f64, Error> {
+    match time_to_seconds(value) {
+        Some(seconds) if seconds.is_finite() && seconds > 0.0 => Ok(seconds),
+        _ => Err(Error::Input(format!(
+            "Invalid value \"{}\" for {}, expected seconds, mm:ss or hh:mm:ss",
+            value, flag
+        ))),
+    }
+}
+
+fn parse_size(flag: &str, value: &str) -> Result<u64, Error> {
+    let number = value.trim_end_matches(['K', 'M', 'G', 'T', 'k', 'm', 'g', 't']);
+    let unit = match value[number.len()..].to_uppercase().as_str() {
+        "" => Some(1u64),
+        "K" => Some(1 << 10),
+        "M" => Some(1 << 20),
+        "G" => Some(1 << 30),
+        "T" => Some(1 << 40),
+        _ => None,
+    };
+    match (number.parse::<f64>(), unit) {
+        (Ok(size), Some(unit)) if size >= 0.0 => Ok((size * unit as f64) as u64),
+        _ => Err(Error::Input(format!(
+            "Invalid value \"{}\" for {}, expected a size such as 500M or 20G",
+            value, flag
+        ))),
+    }
+}
+
+// Checked here because yt-dlp rejecting the rate would only show up as a
+// failed download
+fn parse_rate(flag: &str, value: &str) -> Result<String, Error> {
+    let number = value.trim_end_matches(['K', 'M', 'G', 'k', 'm', 'g']);
+    match number.parse::<f64>() {
+        Ok(rate) if rate > 0.0 && value.len() - number.len() <= 1 => Ok(value.to_string()),
+        _ => Err(Error::Input(format!(
+            "Invalid value \"{}\" for {}, expected a rate such as 500K or 2M",
+            value, flag
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn codecs(copy: bool, container: Option<Container>) -> Result<Config, Error> {
+        let config = Config {
+            copy,
+            container,
+            ..Config::default()
+        };
+        config.check_codecs().map(|_| config)
+    }
+
+    #[test]
+    fn copy_keeps_the_codecs_of_the_source() {
+        let mkv = codecs(true, Some(Container::Mkv)).unwrap();
+        assert!(mkv.stream_copy());
+        assert_eq!(mkv.encoders(), None);
+        assert_eq!(
+            codecs(false, Some(Container::Mkv)).unwrap().encoders(),
+            Some(("libx264", "aac"))
+        );
+        assert_eq!(codecs(true, None).unwrap().encoders(), None);
+        assert!(codecs(true, Some(Container::Webm)).is_err());
+        assert_eq!(
+            codecs(false, Some(Container::Webm)).unwrap().encoders(),
+            Some(("libvpx-vp9", "libopus"))
+        );
+    }
+
+    #[test]
+    fn only_heights_ending_in_p_cap_the_quality() {
+        assert_eq!(
+            parse_quality("720p"),
+            "bestvideo[height<=720]+bestaudio/best[height<=720]"
+        );
+        assert_eq!(parse_quality("18"), "18");
+        assert_eq!(parse_quality("22"), "22");
+        assert_eq!(parse_quality("bestaudio"), "bestaudio");
+    }
+
+    #[test]
+    fn crops_must_fit_in_the_frame() {
+        let data = serde_json::from_str(r#"{"id": "abcdefghijk", "width": 1920, "height": 1080}"#)
+            .unwrap();
+        let video = Video::new("abcdefghijk".to_string(), PathBuf::new(), data);
+        let crop = |s: &str| s.parse::<Crop>().unwrap().check(&video);
+        assert!(crop("1280:720:640:360").is_ok());
+        assert!(crop("1280:720:641:0").is_err());
+        assert!(crop("4000000000:10:400000000:0").is_err());
+        assert!(crop("10:10:4294967295:4294967295").is_err());
+    }
+}
@@ -0,0 +1,966 @@
+use indexmap::IndexMap;
+use log::{debug, error, info, warn};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::{sync::Semaphore, task::JoinSet};
+use youtube_dl::{download_yt_dlp, SingleVideo, YoutubeDl};
+
+use crate::events::{emit, text_output, Event};
+use crate::partial::track_download;
+use crate::{
+    config::{DEFAULT_FFMPEG_PATH, VAAPI_DEVICE},
+    handle_link,
+    progress::{format_bytes, ProgressBar},
+    Config, Error, HwAccel, Progress, Video,
+};
+use crate::{format_time, input::read_rows, organize_videos, playlist_id, time_to_seconds};
+
+// Metadata requests are small, so more of them run at once than downloads
+const METADATA_THREADS: usize = 8;
+
+pub async fn download_video(
+    id: String,
+    data: SingleVideo,
+    cache: HashMap<String, PathBuf>,
+    yt_dlp_path: PathBuf,
+    config: &Config,
+    progress: &Progress,
+) -> Result<Video, Error> {
+    match cache.get(&id) {
+        Some(path) => {
+            info!("Video already downloaded: {}", id);
+            let mut video = Video::new(id, path.clone().to_owned(), data);
+            emit(Event::DownloadFinished {
+                id: video.id.clone(),
+                title: video.title(),
+                path: video.path.clone(),
+                cached: true,
+            });
+            if let Some(lang) = &config.subs {
+                // Cached before --subs was used, fetch just the subtitles
+                if subtitle_path(&config.cache_dir, &video.id, lang).is_none() {
+                    let mut ydl = build_ydl(&video_url(&video.id), &yt_dlp_path, config);
+                    ydl.extra_arg("--skip-download");
+                    throttle(config).await;
+                    if let Err(e) = ydl.download_to_async(&config.cache_dir).await {
+                        warn!("Failed to fetch subtitles for {}: {}", video.id, e);
+                    }
+                }
+            }
+            video.subtitles = find_subtitles(&video, config);
+            Ok(video)
+        }
+        None => {
+            let ydl = build_ydl(&video_url(&id), &yt_dlp_path, config);
+
+            let title = data.title.clone().unwrap_or_else(|| id.clone());
+
+            info!("Downloading video: {}", title);
+            emit(Event::DownloadStarted {
+                id: id.clone(),
+                title: title.clone(),
+            });
+            let total = download_size(&data).unwrap_or(0);
+            let bar = progress.add_bar(format!("Downloading {}", title), total, true);
+            let _partial = track_download(&config.cache_dir, &id);
+            let watcher = tokio::spawn(watch_download(
+                config.cache_dir.clone(),
+                id.clone(),
+                bar.clone(),
+            ));
+            // download_to_async does not report yt-dlp's exit status, so a
+            // missing file is what marks an attempt as failed
+            let result = with_retries(&format!("Downloading video {}", title), config, || async {
+                ydl.download_to_async(&config.cache_dir).await?;
+                check_folder(&config.cache_dir, id.clone())
+            })
+            .await;
+            watcher.abort();
+            bar.finish();
+            let path = result?;
+
+            info!("Downloaded video: {}", title);
+            emit(Event::DownloadFinished {
+                id: id.clone(),
+                title: title.clone(),
+                path: path.clone(),
+                cached: false,
+            });
+            let mut video = Video::new(id.clone(), path, data);
+            video.subtitles = find_subtitles(&video, config);
+            Ok(video)
+        }
+    }
+}
+
+// check_folder, the cache scan and the partial file cleanup all find a
+// download by the <id>. prefix this template gives it, so it is set on every
+// call, the metadata fetch included
+const OUTPUT_TEMPLATE: &str = "%(id)s.%(ext)s";
+
+fn video_url(id: &str) -> String {
+    format!("https://www.youtube.com/watch?v={}", id)
+}
+
+// Every yt-dlp call is built here with all the options of the run, so the
+// metadata fetch and the download see the same format, and a video that needs
+// cookies or a proxy does not fail at whichever call goes without them
+fn build_ydl(url: &str, yt_dlp_path: &Path, config: &Config) -> YoutubeDl {
+    let mut ydl = YoutubeDl::new(url);
+    ydl.youtube_dl_path(yt_dlp_path)
+        .output_template(OUTPUT_TEMPLATE);
+    if let Some(cookies) = &config.cookies {
+        ydl.cookies(cookies.to_string_lossy());
+    }
+    if let Some(browser) = &config.cookies_from_browser {
+        ydl.cookies_from_browser(browser.as_str(), None, None, None);
+    }
+    if let Some(proxy) = &config.proxy {
+        ydl.extra_arg("--proxy").extra_arg(proxy);
+    }
+    if let Some(quality) = &config.quality {
+        ydl.format(quality);
+    }
+    if let Some(lang) = &config.subs {
+        subtitle_args(&mut ydl, lang);
+    }
+    if let Some(rate) = &config.limit_rate {
+        ydl.extra_arg("--limit-rate").extra_arg(rate);
+    }
+    // yt-dlp merges formats with ffmpeg, which must be the one clips are cut with
+    if config.ffmpeg_path != Path::new(DEFAULT_FFMPEG_PATH) {
+        ydl.extra_arg("--ffmpeg-location")
+            .extra_arg(config.ffmpeg_path.to_string_lossy());
+    }
+    // A stalled connection fails the attempt however long the download is
+    if config.download_timeout > 0 {
+        ydl.socket_timeout(config.download_timeout.to_string());
+    }
+    ydl
+}
+
+// What yt-dlp expects to download for the picked format, when YouTube says
+pub(crate) fn download_size(data: &SingleVideo) -> Option<u64> {
+    data.filesize
+        .map(|size| size as u64)
+        .or(data.filesize_approx.map(|size| size as u64))
+}
+
+// Fetches the metadata of every video up front, a few at a time, so the run
+// can be summed up before the first download starts. Cached videos reuse the
+// metadata saved next to them by an earlier run.
+pub async fn prefetch_metadata(
+    ids: &[String],
+    cache: &HashMap<String, PathBuf>,
+    qualities: &HashMap<String, String>,
+    yt_dlp_path: &Path,
+    config: &Config,
+) -> HashMap<String, Result<SingleVideo, Error>> {
+    let limit = Arc::new(Semaphore::new(METADATA_THREADS));
+    let mut tasks = JoinSet::new();
+    let mut metadata = HashMap::new();
+    for id in ids.iter() {
+        let saved = cache
+            .contains_key(id)
+            .then(|| read_info(&config.cache_dir, id))
+            .flatten();
+        if let Some(data) = saved {
+            debug!("Using the saved metadata of video {}", id);
+            metadata.insert(id.clone(), Ok(data));
+            continue;
+        }
+        let id = id.clone();
+        let config = match qualities.get(&id) {
+            Some(quality) => config.with_quality(quality),
+            None => config.clone(),
+        };
+        let ydl = build_ydl(&video_url(&id), yt_dlp_path, &config);
+        let limit = limit.clone();
+        tasks.spawn(async move {
+            let _permit = limit.acquire_owned().await.ok();
+            debug!("Fetching video {}", id);
+            let what = format!("Fetching video {}", id);
+            let result = with_retries(&what, &config, || fetch_metadata(&ydl, &id, &config)).await;
+            if let Ok(data) = &result {
+                write_info(&config.cache_dir, &id, data);
+            }
+            (id, result)
+        });
+    }
+    while let Some(result) = tasks.join_next().await {
+        match result {
+            Ok((id, result)) => {
+                metadata.insert(id, result);
+            }
+            Err(e) => error!("Metadata task failed: {}", e),
+        }
+    }
+    metadata
+}
+
+// Manual subtitles are preferred, automatic captions fill in when there are
+// none. They land in the cache as <id>.<lang>.vtt.
+fn subtitle_args(ydl: &mut YoutubeDl, lang: &str) {
+    ydl.extra_arg("--write-subs")
+        .extra_arg("--write-auto-subs")
+        .extra_arg("--sub-langs")
+        .extra_arg(lang)
+        .extra_arg("--sub-format")
+        .extra_arg("vtt/srt/best");
+}
+
+fn subtitle_path(dir: &Path, id: &str, lang: &str) -> Option<PathBuf> {
+    let prefix = format!("{}.{}.", id, lang);
+    fs::read_dir(dir)
+        .ok()?
+        .flatten()
+        .map(|entry| entry.path())
+        .find(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(&prefix))
+        })
+}
+
+fn find_subtitles(video: &Video, config: &Config) -> Option<PathBuf> {
+    let lang = config.subs.as_deref()?;
+    let path = subtitle_path(&config.cache_dir, &video.id, lang);
+    if path.is_none() {
+        warn!(
+            "No {} subtitles for video {}, clipping without them",
+            lang,
+            video.title()
+        );
+    }
+    path
+}
+
+// Lists the videos of every playlist linked in the input, playlists that
+// cannot be listed are left out and their rows skipped
+pub async fn expand_playlists(
+    config: &Config,
+    yt_dlp_path: &Path,
+) -> Result<HashMap<String, Vec<String>>, Error> {
+    let mut playlists = HashMap::new();
+    for input in config.inputs.iter() {
+        for row in read_rows(input, config)? {
+            let Some(list) = playlist_id(&row.link) else {
+                continue;
+            };
+            if playlists.contains_key(&list) {
+                continue;
+            }
+            let url = format!("https://www.youtube.com/playlist?list={}", list);
+            let mut ydl = build_ydl(&url, yt_dlp_path, config);
+            ydl.flat_playlist(true);
+            let entries = with_retries(&format!("Listing playlist {}", list), config, || async {
+                ydl.run_async()
+                    .await?
+                    .into_playlist()
+                    .ok_or_else(|| Error::Input(format!("{} is not a playlist", list)))
+            })
+            .await;
+            match entries {
+                Ok(playlist) => {
+                    let ids = playlist
+                        .entries
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|entry| entry.id)
+                        .collect::<Vec<String>>();
+                    info!("Playlist {} has {} video(s)", list, ids.len());
+                    playlists.insert(list, ids);
+                }
+                Err(e) => error!("Failed to list playlist {}: {}", list, e),
+            }
+        }
+    }
+    Ok(playlists)
+}
+
+// Saved as <id>.info.json, so the cache cleanup treats it as part of the video
+fn info_path(dir: &Path, id: &str) -> PathBuf {
+    dir.join(format!("{}.info.json", id))
+}
+
+pub(crate) fn read_info(dir: &Path, id: &str) -> Option<SingleVideo> {
+    let json = fs::read_to_string(info_path(dir, id)).ok()?;
+    serde_json::from_str(&json).ok()
+}
+
+fn write_info(dir: &Path, id: &str, data: &SingleVideo) {
+    let path = info_path(dir, id);
+    let written = serde_json::to_string(data)
+        .map_err(std::io::Error::from)
+        .and_then(|json| fs::write(&path, json));
+    if let Err(e) = written {
+        warn!("Could not save {}: {}", path.display(), e);
+    }
+}
+
+// yt-dlp gives up on a stalled connection by itself, --download-timeout also
+// bounds the whole fetch to catch one that keeps trickling. A metadata fetch
+// writes nothing, so the yt-dlp left running when it times out cannot get in
+// the way of the retry. Downloads only get the connection timeout, as the
+// same would leave yt-dlp writing the file the retry resumes.
+async fn fetch_metadata(ydl: &YoutubeDl, id: &str, config: &Config) -> Result<SingleVideo, Error> {
+    let output = match config.download_timeout {
+        0 => ydl.run_async().await?,
+        secs => tokio::time::timeout(Duration::from_secs(secs), ydl.run_async())
+            .await
+            .map_err(|_| Error::Timeout(format!("Fetching the details of {}", id)))??,
+    };
+    output
+        .into_single_video()
+        .ok_or_else(|| Error::NotAVideo(id.to_string()))
+}
+
+// Runs the operation up to --retries times, doubling the delay between
+// attempts starting from --retry-delay
+async fn with_retries<T, F, Fut>(what: &str, config: &Config, mut operation: F) -> Result<T, Error>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T, Error>>,
+{
+    let mut attempt = 1;
+    loop {
+        throttle(config).await;
+        match operation().await {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt < config.retries && !e.is_unavailable() => {
+                let delay = config.retry_delay * 2u64.pow(attempt - 1);
+                warn!(
+                    "{} failed (attempt {}/{}): {}, retrying in {}s",
+                    what, attempt, config.retries, e, delay
+                );
+                tokio::time::sleep(Duration::from_secs(delay)).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// A token bucket shared by every yt-dlp call of the run, holding up to
+// REQUEST_BURST requests and refilled at --requests-per-minute
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+const REQUEST_BURST: f64 = 3.0;
+
+static BUCKET: Mutex<Option<Bucket>> = Mutex::new(None);
+
+// Takes a token, going into debt when there is none, and waits until the
+// debt is paid off, so callers queue up one refill interval apart
+async fn throttle(config: &Config) {
+    let Some(per_minute) = config.requests_per_minute else {
+        return;
+    };
+    let rate = per_minute as f64 / 60.0;
+    let capacity = REQUEST_BURST.min(per_minute as f64);
+    let wait = {
+        let mut bucket = BUCKET.lock().unwrap();
+        let now = Instant::now();
+        let bucket = bucket.get_or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity) - 1.0;
+        bucket.updated = now;
+        (-bucket.tokens / rate).max(0.0)
+    };
+    if wait > 0.0 {
+        debug!("Waiting {:.1}s for --requests-per-minute", wait);
+        tokio::time::sleep(Duration::from_secs_f64(wait)).await;
+    }
+}
+
+// yt-dlp gives no progress callback, so the bar follows the size of the
+// files it is writing into the cache for this id
+async fn watch_download(dir: PathBuf, id: String, bar: ProgressBar) {
+    loop {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let size = fs::read_dir(&dir)
+            .map(|entries| {
+                entries
+                    .flatten()
+                    .filter(|entry| entry.file_name().to_string_lossy().starts_with(&id))
+                    .filter_map(|entry| entry.metadata().ok())
+                    .map(|metadata| metadata.len())
+                    .sum()
+            })
+            .unwrap_or(0);
+        bar.set_position(size);
+    }
+}
+
+// Sidecars such as <id>.en.vtt, <id>.webp or <id>.part are never the video,
+// and a known video extension wins over anything else left under the id
+pub fn check_folder(dir: &Path, id: String) -> Result<PathBuf, Error> {
+    let mut found = None;
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !matches!(cache_entry(&path), CacheEntry::Video(stem) if stem == id) {
+            continue;
+        }
+        if has_video_extension(&path) {
+            return Ok(path);
+        }
+        found.get_or_insert(path);
+    }
+    found.ok_or(Error::VideoNotFound(id))
+}
+
+const VIDEO_EXTENSIONS: [&str; 5] = ["mp4", "mkv", "webm", "mov", "flv"];
+
+fn has_video_extension(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| VIDEO_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+}
+
+// loudnorm, the newest filter clips can use, arrived in FFmpeg 3.1
+const MIN_FFMPEG_VERSION: (u32, u32) = (3, 1);
+const FFMPEG_HELP: &str = "install it with `winget install ffmpeg` on Windows, \
+`sudo apt install ffmpeg` on Debian or Ubuntu or `brew install ffmpeg` on macOS, \
+or point --ffmpeg-path at an existing binary";
+
+fn check_ffmpeg(ffmpeg_path: &Path) -> Result<(), Error> {
+    let version = match ffmpeg_version(ffmpeg_path) {
+        Some(version) => version,
+        // Only a bare `ffmpeg` is installed for the user, a custom path that
+        // does not run is a mistake to report
+        None if ffmpeg_path == Path::new(DEFAULT_FFMPEG_PATH) => {
+            warn!("FFmpeg is not installed, trying to install it");
+            download_ffmpeg()?;
+            ffmpeg_version(ffmpeg_path).ok_or_else(|| {
+                Error::Ffmpeg(format!(
+                    "FFmpeg still does not run after installing, {}",
+                    FFMPEG_HELP
+                ))
+            })?
+        }
+        None => {
+            return Err(Error::Ffmpeg(format!(
+                "{} does not run, {}",
+                ffmpeg_path.display(),
+                FFMPEG_HELP
+            )))
+        }
+    };
+    match version {
+        Some(version) if version < MIN_FFMPEG_VERSION => Err(Error::Ffmpeg(format!(
+            "FFmpeg {}.{} is too old, at least {}.{} is needed, {}",
+            version.0, version.1, MIN_FFMPEG_VERSION.0, MIN_FFMPEG_VERSION.1, FFMPEG_HELP
+        ))),
+        _ => Ok(()),
+    }
+}
+
+// None when ffmpeg does not run. Git builds report something like
+// "N-113000-g1234" in place of a version, and are taken to be recent.
+fn ffmpeg_version(ffmpeg_path: &Path) -> Option<Option<(u32, u32)>> {
+    let output = Command::new(ffmpeg_path).arg("-version").output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let version = stdout
+        .split_whitespace()
+        .skip_while(|word| *word != "version")
+        .nth(1)
+        .unwrap_or_default()
+        .trim_start_matches('n');
+    let mut numbers = version
+        .split(|c: char| !c.is_ascii_digit())
+        .map(|number| number.parse::<u32>());
+    match (numbers.next(), numbers.next()) {
+        (Some(Ok(major)), Some(Ok(minor))) => Some(Some((major, minor))),
+        _ => Some(None),
+    }
+}
+
+// The encoder can be built into ffmpeg while the GPU or its driver is
+// missing, so it is tried on a short test pattern
+pub fn check_hwaccel(config: &Config) -> bool {
+    let Some(encoder) = config.encoders().map(|(video, _)| video) else {
+        return true;
+    };
+    let mut command = Command::new(&config.ffmpeg_path);
+    command.arg("-hide_banner").arg("-loglevel").arg("error");
+    if config.hw_encoder() == Some(HwAccel::Vaapi) {
+        command.arg("-vaapi_device").arg(VAAPI_DEVICE);
+    }
+    command
+        .arg("-f")
+        .arg("lavfi")
+        .arg("-i")
+        .arg("testsrc=size=256x256:duration=0.1");
+    if config.hw_encoder() == Some(HwAccel::Vaapi) {
+        command.arg("-vf").arg("format=nv12,hwupload");
+    }
+    let output = command
+        .arg("-c:v")
+        .arg(encoder)
+        .arg("-f")
+        .arg("null")
+        .arg("-")
+        .output();
+    match output {
+        Ok(output) if output.status.success() => true,
+        Ok(output) => {
+            debug!(
+                "{} failed: {}",
+                encoder,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            false
+        }
+        Err(_) => false,
+    }
+}
+
+fn download_ffmpeg() -> Result<(), Error> {
+    let install = if cfg!(windows) {
+        Command::new("winget").arg("install").arg("ffmpeg").output()
+    } else if cfg!(unix) {
+        Command::new("apt")
+            .arg("install")
+            .arg("-y")
+            .arg("ffmpeg")
+            .arg("libavutil-dev")
+            .arg("libavformat-dev")
+            .arg("libavcodec-dev")
+            .arg("libavdevice-dev")
+            .arg("libavfilter-dev")
+            .arg("libswscale-dev")
+            .arg("libswresample-dev")
+            .arg("libpostproc-dev")
+            .arg("libclang-dev")
+            .output()
+    } else {
+        return Err(Error::Input("Unsupported OS".to_string()));
+    };
+    let installer = if cfg!(windows) { "Winget" } else { "Apt" };
+    match install {
+        Ok(output) if output.status.success() => Ok(()),
+        Ok(output) => Err(Error::Ffmpeg(format!(
+            "{} failed to install FFmpeg ({}), {}",
+            installer,
+            String::from_utf8_lossy(&output.stderr).trim(),
+            FFMPEG_HELP
+        ))),
+        Err(_) => Err(Error::Ffmpeg(format!(
+            "{} is not installed, {}",
+            installer, FFMPEG_HELP
+        ))),
+    }
+}
+
+pub async fn setup(config: &Config) -> Result<PathBuf, Error> {
+    check_ffmpeg(&config.ffmpeg_path)?;
+    if config.verify
+        && Command::new(config.ffprobe_path())
+            .arg("-version")
+            .output()
+            .is_err()
+    {
+        return Err(Error::Ffmpeg(format!(
+            "--verify needs ffprobe, which was not found at {}",
+            config.ffprobe_path().display()
+        )));
+    }
+    let yt_dlp_path = setup_yt_dlp(config).await?;
+
+    for dir in [&config.cache_dir, &config.output_dir] {
+        if create_dir_tree(dir)? {
+            info!("Directory created: {}", dir.display());
+        }
+    }
+
+    Ok(yt_dlp_path)
+}
+
+pub async fn setup_yt_dlp(config: &Config) -> Result<PathBuf, Error> {
+    let path = "./lib";
+    let yt_dlp_path: PathBuf = if cfg!(windows) {
+        match fs::File::open(format!("{}/yt-dlp.exe", path)) {
+            Ok(_) => PathBuf::from(format!("{}/yt-dlp.exe", path)),
+            Err(_) => {
+                info!("Downloading yt-dlp");
+                download_yt_dlp(format!("{}/", path)).await?
+            }
+        }
+    } else if cfg!(unix) {
+        match fs::File::open(format!("{}/yt-dlp", path)) {
+            Ok(_) => PathBuf::from(format!("{}/yt-dlp", path)),
+            Err(_) => {
+                info!("Downloading yt-dlp");
+                download_yt_dlp(format!("{}/", path)).await?
+            }
+        }
+    } else {
+        return Err(Error::Input("Unsupported OS".to_string()));
+    };
+    update_yt_dlp(yt_dlp_path, path, config).await
+}
+
+// Checks every row and fetches the metadata of every video without downloading,
+// failing with the whole list of problems found
+pub async fn probe(config: &Config) -> Result<(), Error> {
+    let yt_dlp_path = setup_yt_dlp(config).await?;
+    let playlists = match config.expand_playlists {
+        true => expand_playlists(config, &yt_dlp_path).await?,
+        false => HashMap::new(),
+    };
+    let mut videos = IndexMap::new();
+    let mut problems = organize_videos(config, &playlists, &mut videos)?;
+    for (id, clips) in videos.iter() {
+        info!("Probing video {}", id);
+        let config = match clips.iter().find_map(|clip| clip.quality.as_ref()) {
+            Some(quality) => &config.with_quality(quality),
+            None => config,
+        };
+        let ydl = build_ydl(&video_url(id), &yt_dlp_path, config);
+        let what = format!("Probing video {}", id);
+        let data = match with_retries(&what, config, || fetch_metadata(&ydl, id, config)).await {
+            Ok(data) => data,
+            Err(e) => {
+                problems.push(format!("video {}: {}", id, e.reason()));
+                continue;
+            }
+        };
+        let duration = data
+            .duration
+            .as_ref()
+            .and_then(|duration| duration.as_f64());
+        for clip in clips.iter() {
+            let mut clip = clip.clone();
+            if let Err(e) = clip.resolve_times(duration) {
+                problems.push(format!("video {}: clip {}", id, e));
+                continue;
+            }
+            let start = time_to_seconds(&clip.start_time).unwrap_or(0.0);
+            if duration.is_some_and(|duration| start >= duration) {
+                problems.push(format!(
+                    "video {}: clip {} starts after the video ends at {}",
+                    id,
+                    clip.start_time,
+                    format_time(duration.unwrap_or(0.0))
+                ));
+            }
+        }
+    }
+    if problems.is_empty() {
+        info!("All {} video(s) and their clips are fine", videos.len());
+        return Ok(());
+    }
+    Err(Error::Input(format!(
+        "{} problem(s) found:\n  {}",
+        problems.len(),
+        problems.join("\n  ")
+    )))
+}
+
+// Prints what yt-dlp can download for a link, to pick a --quality from
+pub async fn list_formats(link: &str, config: &Config) -> Result<(), Error> {
+    let yt_dlp_path = setup_yt_dlp(config).await?;
+    let link = handle_link(link.trim());
+    // A --quality the video does not offer would fail the listing meant to
+    // find a better one
+    let config = &Config {
+        quality: None,
+        ..config.clone()
+    };
+    let ydl = build_ydl(&link, &yt_dlp_path, config);
+    let data = with_retries(&format!("Fetching formats of {}", link), config, || {
+        fetch_metadata(&ydl, &link, config)
+    })
+    .await?;
+    let mut out = text_output();
+    writeln!(out, "{}", data.title.as_deref().unwrap_or(&link))?;
+    writeln!(
+        out,
+        "{:<10} {:<5} {:<11} {:>4} {:<14} {:<12} {:>10}",
+        "ID", "EXT", "RESOLUTION", "FPS", "VIDEO", "AUDIO", "SIZE"
+    )?;
+    for format in data.formats.unwrap_or_default() {
+        let codec = |codec: Option<String>| match codec.as_deref() {
+            Some("none") | None => "-".to_string(),
+            Some(codec) => codec.to_string(),
+        };
+        let size = format
+            .filesize
+            .or(format.filesize_approx)
+            .map_or(String::new(), |size| format_bytes(size as u64));
+        writeln!(
+            out,
+            "{:<10} {:<5} {:<11} {:>4} {:<14} {:<12} {:>10}",
+            format.format_id.unwrap_or_default(),
+            format.ext.unwrap_or_default(),
+            format.resolution.unwrap_or_default(),
+            format
+                .fps
+                .map_or(String::new(), |fps| fps.round().to_string()),
+            codec(format.vcodec),
+            codec(format.acodec),
+            size
+        )?;
+    }
+    Ok(())
+}
+
+// YouTube changes break old yt-dlp releases often, so the binary is updated
+// once it has gone a week without a check
+const YT_DLP_MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+const YT_DLP_STAMP: &str = "yt-dlp.updated";
+
+async fn update_yt_dlp(yt_dlp_path: PathBuf, dir: &str, config: &Config) -> Result<PathBuf, Error> {
+    let stamp = Path::new(dir).join(YT_DLP_STAMP);
+    // The binary's own age stands in until the first check writes the stamp
+    let checked = [stamp.as_path(), yt_dlp_path.as_path()]
+        .iter()
+        .filter_map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+        .max();
+    let stale = checked
+        .and_then(|time| SystemTime::now().duration_since(time).ok())
+        .is_none_or(|age| age > YT_DLP_MAX_AGE);
+    if !config.update_ytdlp && !stale {
+        return Ok(yt_dlp_path);
+    }
+
+    info!("Updating yt-dlp");
+    let mut update = Command::new(&yt_dlp_path);
+    update.arg("-U");
+    if let Some(proxy) = &config.proxy {
+        update.arg("--proxy").arg(proxy);
+    }
+    let updated = update.output().is_ok_and(|output| output.status.success());
+    // Being offline should not stop a run the binary at hand can still do,
+    // the stamp is left alone so the next run tries again
+    let yt_dlp_path = match updated {
+        true => yt_dlp_path,
+        false => {
+            warn!("yt-dlp -U failed, downloading the latest release");
+            match download_yt_dlp(format!("{}/", dir)).await {
+                Ok(path) => path,
+                Err(e) => {
+                    warn!("Could not update yt-dlp ({}), using the current one", e);
+                    return Ok(yt_dlp_path);
+                }
+            }
+        }
+    };
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default();
+    fs::write(stamp, now.to_string())?;
+    Ok(yt_dlp_path)
+}
+
+fn create_dir_tree(dir: &Path) -> Result<bool, Error> {
+    if dir.is_dir() {
+        return Ok(false);
+    }
+    fs::create_dir_all(dir)
+        .map_err(|e| Error::Input(format!("Cannot create {}: {}", dir.display(), e)))?;
+    Ok(true)
+}
+
+// Incomplete downloads are never reported as cached, and only removed with
+// remove_invalid, so a dry run leaves the cache and any download in progress
+// alone
+pub fn check_cache(
+    cache_dir: &Path,
+    downloaded: &mut HashMap<String, PathBuf>,
+    remove_invalid: bool,
+) -> bool {
+    info!("Checking cache...");
+    if let Ok(entries) = fs::read_dir(cache_dir) {
+        let mut dropped = Vec::new();
+        for entry in entries.flatten() {
+            let path = entry.path();
+            match cache_entry(&path) {
+                CacheEntry::Video(id) => {
+                    downloaded.insert(id, path.clone());
+                }
+                CacheEntry::Invalid if remove_invalid => dropped.push(path),
+                CacheEntry::Invalid => debug!("Ignoring incomplete download {}", path.display()),
+                CacheEntry::Other => {}
+            }
+        }
+        for path in dropped {
+            warn!("Dropping incomplete download {}", path.display());
+            if let Err(e) = fs::remove_file(&path) {
+                error!("Failed to remove {}: {}", path.display(), e);
+            }
+        }
+        true
+    } else {
+        warn!("Failed to read the cache directory {}", cache_dir.display());
+        false
+    }
+}
+
+enum CacheEntry {
+    Video(String),
+    // Left behind by an interrupted download, removed so yt-dlp starts over
+    // instead of trusting it
+    Invalid,
+    // Subtitles, unmerged formats and anything else that is not a video
+    Other,
+}
+
+const PARTIAL_EXTENSIONS: [&str; 3] = ["part", "ytdl", "temp"];
+const SIDECAR_EXTENSIONS: [&str; 7] = ["vtt", "srt", "ass", "json", "jpg", "webp", "png"];
+
+fn cache_entry(path: &Path) -> CacheEntry {
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+    let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) else {
+        return CacheEntry::Other;
+    };
+    if PARTIAL_EXTENSIONS.contains(&extension.as_str()) {
+        return CacheEntry::Invalid;
+    }
+    // Video ids never contain a dot, <id>.en.vtt or <id>.f137.mp4 are not
+    // finished videos
+    if SIDECAR_EXTENSIONS.contains(&extension.as_str()) || stem.contains('.') || !path.is_file() {
+        return CacheEntry::Other;
+    }
+    match fs::metadata(path) {
+        Ok(metadata) if metadata.len() > 0 => CacheEntry::Video(stem.to_string()),
+        _ => CacheEntry::Invalid,
+    }
+}
+//sudo apt install ffmpeg libavutil-dev libavformat-dev libavcodec-dev libavdevice-dev libavfilter-dev libswscale-dev libswresample-dev libpostproc-dev libclang-dev
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("clip_downloader_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // A stand-in yt-dlp records the arguments it was started with
+    #[cfg(unix)]
+    async fn yt_dlp_args(name: &str, config: &Config) -> Vec<String> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = temp_dir(name);
+        let yt_dlp = dir.join("yt-dlp");
+        let args = dir.join("args");
+        let script = format!("#!/bin/sh\nprintf '%s\\n' \"$@\" > '{}'\n", args.display());
+        fs::write(&yt_dlp, script).unwrap();
+        fs::set_permissions(&yt_dlp, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let ydl = build_ydl(&video_url("abcdefghijk"), &yt_dlp, config);
+        ydl.download_to_async(&dir).await.unwrap();
+        let args = fs::read_to_string(&args).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        args.lines().map(String::from).collect()
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn download_is_given_the_output_template() {
+        let args = yt_dlp_args("template", &Config::default()).await;
+        assert!(args.windows(2).any(|pair| pair == ["-o", OUTPUT_TEMPLATE]));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn yt_dlp_merges_with_the_configured_ffmpeg() {
+        let args = yt_dlp_args("ffmpeg_default", &Config::default()).await;
+        assert!(!args.iter().any(|arg| arg == "--ffmpeg-location"));
+
+        let config = Config {
+            ffmpeg_path: PathBuf::from("/opt/ffmpeg/bin/ffmpeg"),
+            ..Config::default()
+        };
+        let args = yt_dlp_args("ffmpeg_custom", &config).await;
+        assert!(args
+            .windows(2)
+            .any(|pair| pair == ["--ffmpeg-location", "/opt/ffmpeg/bin/ffmpeg"]));
+    }
+
+    #[test]
+    fn empty_and_partial_downloads_are_not_cached() {
+        let dir = temp_dir("cache");
+        fs::write(dir.join("emptyvideo1.mp4"), "").unwrap();
+        fs::write(dir.join("partvideo01.mp4.part"), "data").unwrap();
+        fs::write(dir.join("fullvideo01.mp4"), "data").unwrap();
+
+        let mut cached = HashMap::new();
+        assert!(check_cache(&dir, &mut cached, false));
+        assert_eq!(cached.keys().collect::<Vec<_>>(), ["fullvideo01"]);
+        assert!(dir.join("emptyvideo1.mp4").exists());
+        assert!(dir.join("partvideo01.mp4.part").exists());
+
+        let mut cached = HashMap::new();
+        assert!(check_cache(&dir, &mut cached, true));
+        assert_eq!(cached.keys().collect::<Vec<_>>(), ["fullvideo01"]);
+        assert!(!dir.join("emptyvideo1.mp4").exists());
+        assert!(!dir.join("partvideo01.mp4.part").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn subtitles_next_to_a_download_are_not_the_video() {
+        let dir = temp_dir("folder");
+        fs::write(dir.join("abcdefghijk.en.vtt"), "WEBVTT").unwrap();
+        fs::write(dir.join("abcdefghijk.info.json"), "{}").unwrap();
+        assert!(matches!(
+            check_folder(&dir, "abcdefghijk".to_string()),
+            Err(Error::VideoNotFound(_))
+        ));
+
+        fs::write(dir.join("abcdefghijk.mp4"), "data").unwrap();
+        let found = check_folder(&dir, "abcdefghijk".to_string()).unwrap();
+        assert_eq!(found, dir.join("abcdefghijk.mp4"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn directories_that_cannot_be_created_are_errors() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = temp_dir("setup");
+        assert!(create_dir_tree(&dir.join("cache")).unwrap());
+        assert!(!create_dir_tree(&dir.join("cache")).unwrap());
+
+        fs::write(dir.join("file"), "").unwrap();
+        let error = create_dir_tree(&dir.join("file").join("cache")).unwrap_err();
+        assert!(error.to_string().contains("Cannot create"), "{}", error);
+
+        let read_only = dir.join("read_only");
+        fs::create_dir(&read_only).unwrap();
+        fs::set_permissions(&read_only, fs::Permissions::from_mode(0o555)).unwrap();
+        // Permissions do not stop root, there is nothing to check then
+        if fs::create_dir(read_only.join("probe")).is_err() {
+            let error = create_dir_tree(&read_only.join("cache")).unwrap_err();
+            assert!(error.to_string().contains("Permission denied"), "{}", error);
+        }
+        fs::set_permissions(&read_only, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,65 @@
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Spreadsheet error: {0}")]
+    Spreadsheet(#[from] calamine::XlsxError),
+    #[error("yt-dlp error: {0}")]
+    YoutubeDl(#[from] youtube_dl::Error),
+    #[error("FFmpeg error: {0}")]
+    Ffmpeg(String),
+    #[error("{0}")]
+    Input(String),
+    #[error("\"{0}\" is not a valid time, expected ss, mm:ss, hh:mm:ss or a percentage")]
+    InvalidTime(String),
+    #[error("\"{0}\" is not a valid time, {1}")]
+    InvalidTimeField(String, String),
+    #[error("\"{0}\" is not a YouTube video link")]
+    InvalidLink(String),
+    #[error("{0} is not a single video")]
+    NotAVideo(String),
+    #[error("Video {0} not found in cache")]
+    VideoNotFound(String),
+    #[error("Archive error: {0}")]
+    Archive(#[from] zip::result::ZipError),
+    #[error("{0} timed out")]
+    Timeout(String),
+}
+
+impl Error {
+    // yt-dlp's stderr ends in an "ERROR: ..." line that says why a video
+    // could not be fetched, which is all the summary needs
+    pub fn reason(&self) -> String {
+        match self {
+            Error::YoutubeDl(youtube_dl::Error::ExitCode { stderr, .. }) => stderr
+                .lines()
+                .rev()
+                .find_map(|line| line.strip_prefix("ERROR: "))
+                .unwrap_or(stderr.trim())
+                .to_string(),
+            e => e.to_string(),
+        }
+    }
+    // A failed ffmpeg run or file write may succeed when tried again, bad
+    // input will not
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Error::Ffmpeg(_) | Error::Io(_))
+    }
+    // Retrying will not bring back a private, removed or geo-blocked video
+    pub fn is_unavailable(&self) -> bool {
+        const MARKERS: [&str; 6] = [
+            "Private video",
+            "Video unavailable",
+            "This video has been removed",
+            "not available in your country",
+            "This video is not available",
+            "members-only",
+        ];
+        match self {
+            Error::YoutubeDl(youtube_dl::Error::ExitCode { stderr, .. }) => {
+                MARKERS.iter().any(|marker| stderr.contains(marker))
+            }
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use serde::Serialize;
+use std::{
+    io::Write,
+    path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::report::ClipResult;
+
+// With --output-format json the run is reported as one JSON object per line
+// on stdout, for scripts and CI to follow, instead of the human messages
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    DownloadStarted {
+        id: String,
+        title: String,
+    },
+    DownloadFinished {
+        id: String,
+        title: String,
+        path: PathBuf,
+        cached: bool,
+    },
+    ClipDone(ClipResult),
+    Error {
+        message: String,
+    },
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn enable_events(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub(crate) fn events_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+// Text meant to be read, such as a plan or a listing, goes to stderr in JSON
+// mode so stdout only carries events
+pub(crate) fn text_output() -> Box<dyn Write> {
+    match events_enabled() {
+        true => Box::new(std::io::stderr()),
+        false => Box::new(std::io::stdout()),
+    }
+}
+
+pub(crate) fn emit(event: Event) {
+    if !events_enabled() {
+        return;
+    }
+    if let Ok(line) = serde_json::to_string(&event) {
+        let mut stdout = std::io::stdout().lock();
+        let _ = writeln!(stdout, "{}", line);
+        let _ = stdout.flush();
+    }
+}
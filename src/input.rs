@@ -0,0 +1,658 @@
+use calamine::{open_workbook, Data, Reader, Xlsx};
+use indexmap::IndexMap;
+use log::{info, warn};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::config::parse_quality;
+use crate::events::text_output;
+use crate::{
+    format_time, link_timestamp, playlist_id, time_to_seconds, Columns, Config, Error,
+    OutputFormat, OverlapMode, VideoLink,
+};
+
+#[derive(Debug, Clone)]
+pub struct ClipRow {
+    pub row: usize,
+    pub start: String,
+    pub end: String,
+    pub link: String,
+    pub name: String,
+    pub format: String,
+    pub quality: String,
+}
+
+// Playlists maps list ids to their video ids, see expand_playlists. Returns
+// why each skipped row was skipped.
+pub fn organize_videos(
+    config: &Config,
+    playlists: &HashMap<String, Vec<String>>,
+    videos: &mut IndexMap<String, Vec<VideoLink>>,
+) -> Result<Vec<String>, Error> {
+    let mut skipped = Vec::new();
+    info!("Organizing videos...");
+    let mut rows = Vec::new();
+    for input in config.inputs.iter() {
+        let input_rows = read_rows(input, config)?;
+        if input_rows.is_empty() {
+            return Err(Error::Input(format!("No data found in {}", input)));
+        }
+        rows.extend(input_rows.into_iter().map(|row| (input, row)));
+    }
+
+    // Clips of a video accumulate across inputs, in the order they are given
+    for (input, row) in rows.iter() {
+        let i = match config.inputs.len() {
+            1 => row.row.to_string(),
+            _ => format!("{} of {}", row.row, input),
+        };
+        let (data_start_time, data_end_time) = match row.end.trim().is_empty() {
+            true => split_range(&row.start).unwrap_or((&row.start, &row.end)),
+            false => (row.start.as_str(), row.end.as_str()),
+        };
+        let links = match playlist_id(&row.link).filter(|_| config.expand_playlists) {
+            Some(list) => match playlists.get(&list) {
+                Some(ids) => ids
+                    .iter()
+                    .map(|id| format!("https://www.youtube.com/watch?v={}", id))
+                    .collect(),
+                None => {
+                    let problem = format!("row {}: playlist {} was not expanded", i, list);
+                    skip_row(&mut skipped, problem);
+                    continue;
+                }
+            },
+            None => vec![row.link.clone()],
+        };
+
+        for data_link in links.iter() {
+            let data_link = data_link.as_str();
+            let mut video = match VideoLink::new(data_link) {
+                Ok(video) => video,
+                Err(e) => {
+                    skip_row(&mut skipped, format!("row {}: {}", i, e));
+                    continue;
+                }
+            };
+            // A t= timestamp in the link starts the clip when the start is blank
+            let timestamp = data_start_time
+                .trim()
+                .is_empty()
+                .then(|| link_timestamp(data_link))
+                .flatten();
+            let (data_start_time, data_end_time) = match &timestamp {
+                Some(start) if data_end_time.trim().is_empty() => (start.as_str(), "END"),
+                Some(start) => (start.as_str(), data_end_time),
+                None => (data_start_time, data_end_time),
+            };
+            let whole = config.full
+                || (data_start_time.trim().is_empty() && data_end_time.trim().is_empty());
+            let times = match whole {
+                true => video
+                    .set_start_time("0")
+                    .and_then(|_| video.set_end_time("END")),
+                false => video
+                    .set_start_time(data_start_time)
+                    .and_then(|_| video.set_end_time(data_end_time)),
+            };
+            // Chapters replace the ranges, so a row only needs a usable link and
+            // falls back to nothing when its times are unusable
+            if config.by_chapters
+                && (times.is_err() || video.duration().is_some_and(|duration| duration <= 0.0))
+            {
+                videos.entry(video.id).or_default();
+                continue;
+            }
+            if let Err(e) = times {
+                skip_row(&mut skipped, format!("row {}: {} ({})", i, e, data_link));
+                continue;
+            }
+
+            let name = row.name.trim();
+            if !name.is_empty() {
+                video.name = Some(name.to_string());
+            }
+
+            let format = row.format.trim();
+            if !format.is_empty() {
+                match format.parse::<OutputFormat>() {
+                    Ok(_) if config.concat => {
+                        warn!(
+                            "Ignoring the format of row {}, --concat joins clips in one format",
+                            i
+                        )
+                    }
+                    Ok(format) => video.format = Some(format),
+                    Err(e) => {
+                        skip_row(&mut skipped, format!("row {}: {}", i, e));
+                        continue;
+                    }
+                }
+            }
+
+            let quality = row.quality.trim();
+            if !quality.is_empty() {
+                video.quality = Some(parse_quality(quality));
+            }
+
+            if video.duration().is_some_and(|duration| duration <= 0.0) {
+                let problem = format!(
+                    "row {}: end {} is not after start {} ({})",
+                    i, data_end_time, data_start_time, data_link
+                );
+                skip_row(&mut skipped, problem);
+                continue;
+            }
+
+            let video_id = video.id.to_owned();
+
+            if let indexmap::map::Entry::Vacant(e) = videos.entry(video_id) {
+                e.insert(vec![video.clone()]);
+            } else {
+                videos
+                    .get_mut(video.id.as_str())
+                    .unwrap()
+                    .push(video.clone());
+            }
+        }
+    }
+    let mut duplicates = 0;
+    for clips in videos.values_mut() {
+        duplicates += remove_duplicates(clips);
+        resolve_overlaps(clips, config.overlaps);
+    }
+    if duplicates > 0 {
+        info!("Removed {} duplicate clip(s)", duplicates);
+    }
+    Ok(skipped)
+}
+
+// Every video the input refers to, whatever the times of its rows, for
+// --clean-cache to keep. A playlist that was not expanded fails, since
+// its videos would be removed otherwise.
+pub fn listed_videos(
+    config: &Config,
+    playlists: &HashMap<String, Vec<String>>,
+) -> Result<HashSet<String>, Error> {
+    let mut videos = HashSet::new();
+    for input in config.inputs.iter() {
+        let rows = read_rows(input, config)?;
+        if rows.is_empty() {
+            return Err(Error::Input(format!("No data found in {}", input)));
+        }
+        for row in rows.iter() {
+            match playlist_id(&row.link).filter(|_| config.expand_playlists) {
+                Some(list) => match playlists.get(&list) {
+                    Some(ids) => videos.extend(ids.iter().cloned()),
+                    None => {
+                        return Err(Error::Input(format!(
+                            "row {} of {}: playlist {} was not expanded",
+                            row.row, input, list
+                        )))
+                    }
+                },
+                None => {
+                    if let Ok(video) = VideoLink::new(&row.link) {
+                        videos.insert(video.id);
+                    }
+                }
+            }
+        }
+    }
+    Ok(videos)
+}
+
+fn skip_row(skipped: &mut Vec<String>, problem: String) {
+    warn!("Skipping {}", problem);
+    skipped.push(problem);
+}
+
+// Times are already in canonical form, so equal ranges compare equal as
+// strings. The first row of a range keeps its name.
+fn remove_duplicates(clips: &mut Vec<VideoLink>) -> usize {
+    let before = clips.len();
+    let mut seen = HashSet::new();
+    clips.retain(|clip| seen.insert((clip.start_time.clone(), clip.end_time.clone(), clip.format)));
+    before - clips.len()
+}
+
+// A clip without an end runs to the end of the video, past any other start
+fn range(clip: &VideoLink) -> (f64, f64) {
+    let start = time_to_seconds(&clip.start_time).unwrap_or(0.0);
+    let end = clip
+        .end_time
+        .as_deref()
+        .and_then(time_to_seconds)
+        .unwrap_or(f64::INFINITY);
+    (start, end)
+}
+
+fn describe(clip: &VideoLink) -> String {
+    format!(
+        "{}-{}",
+        clip.start_time,
+        clip.end_time.as_deref().unwrap_or("END")
+    )
+}
+
+fn resolve_overlaps(clips: &mut Vec<VideoLink>, mode: OverlapMode) {
+    let mut kept: Vec<VideoLink> = Vec::new();
+    let mut order = clips.clone();
+    if mode == OverlapMode::Merge {
+        order.sort_by(|a, b| range(a).0.total_cmp(&range(b).0));
+    }
+    for clip in order {
+        // Where a relative range lies is only known once the video is
+        if clip.is_relative() {
+            kept.push(clip);
+            continue;
+        }
+        let (start, end) = range(&clip);
+        let Some(other) = kept.iter_mut().find(|other| {
+            let (other_start, other_end) = range(other);
+            !other.is_relative() && start < other_end && other_start < end
+        }) else {
+            kept.push(clip);
+            continue;
+        };
+        match mode {
+            OverlapMode::Keep => {
+                warn!(
+                    "Video {}: clip {} overlaps clip {}",
+                    clip.id,
+                    describe(&clip),
+                    describe(other)
+                );
+                kept.push(clip);
+            }
+            OverlapMode::First => warn!(
+                "Video {}: dropping clip {}, it overlaps clip {}",
+                clip.id,
+                describe(&clip),
+                describe(other)
+            ),
+            OverlapMode::Merge => {
+                let merged = describe(other);
+                if end > range(other).1 {
+                    other.end_time = clip.end_time.clone();
+                }
+                warn!(
+                    "Video {}: merged clip {} into {}, now {}",
+                    clip.id,
+                    describe(&clip),
+                    merged,
+                    describe(other)
+                );
+            }
+        }
+    }
+    *clips = kept;
+}
+
+// A start cell such as 1:20-1:45 holding the whole range
+fn split_range(cell: &str) -> Option<(&str, &str)> {
+    let (start, end) = cell.split_once(['-', '–'])?;
+    (!start.trim().is_empty() && !end.trim().is_empty()).then_some((start, end))
+}
+
+// Lists the clips for --select and keeps the ones picked on the terminal
+pub fn select_clips(videos: &mut IndexMap<String, Vec<VideoLink>>) -> Result<(), Error> {
+    let total = videos.values().map(|clips| clips.len()).sum::<usize>();
+    let mut out = text_output();
+    let mut number = 0;
+    for (id, clips) in videos.iter() {
+        writeln!(out, "Video {}", id)?;
+        for clip in clips.iter() {
+            number += 1;
+            writeln!(
+                out,
+                "  {:>3}. {} -> {}{}",
+                number,
+                clip.start_time,
+                clip.end_time.as_deref().unwrap_or("END"),
+                clip.name
+                    .as_ref()
+                    .map_or(String::new(), |name| format!("  {}", name))
+            )?;
+        }
+    }
+    let selected = loop {
+        write!(out, "Clips to process, e.g. 1-3,5 [all]: ")?;
+        out.flush()?;
+        let mut line = String::new();
+        if std::io::stdin().read_line(&mut line)? == 0 {
+            return Err(Error::Input("No clips selected".to_string()));
+        }
+        match parse_selection(line.trim(), total) {
+            Ok(selected) => break selected,
+            Err(e) => writeln!(out, "{}", e)?,
+        }
+    };
+    keep_selected(videos, &selected);
+    info!("Processing {} of {} clip(s)", selected.len(), total);
+    Ok(())
+}
+
+// Numbers count across all videos, in the order they were listed
+fn keep_selected(videos: &mut IndexMap<String, Vec<VideoLink>>, selected: &HashSet<usize>) {
+    let mut number = 0;
+    for clips in videos.values_mut() {
+        for (clip, i) in clips.iter_mut().zip(1..) {
+            clip.number = Some(i);
+        }
+        clips.retain(|_| {
+            number += 1;
+            selected.contains(&number)
+        });
+    }
+    videos.retain(|_, clips| !clips.is_empty());
+}
+
+// Comma separated numbers and ranges of the listed clips, blank or "all" for all
+fn parse_selection(line: &str, total: usize) -> Result<HashSet<usize>, String> {
+    if line.is_empty() || line.eq_ignore_ascii_case("all") {
+        return Ok((1..=total).collect());
+    }
+    let mut selected = HashSet::new();
+    for part in line
+        .split(',')
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+    {
+        let number = |s: &str| {
+            s.trim()
+                .parse::<usize>()
+                .ok()
+                .filter(|n| (1..=total).contains(n))
+                .ok_or_else(|| format!("\"{}\" is not a clip number from 1 to {}", s.trim(), total))
+        };
+        let (first, last) = match part.split_once('-') {
+            Some((first, last)) => (number(first)?, number(last)?),
+            None => (number(part)?, number(part)?),
+        };
+        selected.extend(first.min(last)..=first.max(last));
+    }
+    match selected.is_empty() {
+        true => Err("Pick at least one clip".to_string()),
+        false => Ok(selected),
+    }
+}
+
+pub fn read_rows(input: &str, config: &Config) -> Result<Vec<ClipRow>, Error> {
+    if input == STDIN_INPUT {
+        return Ok(to_clip_rows(read_stdin_cells()?, config.columns));
+    }
+    // A bare name keeps the original behavior of reading `./<name>.xlsx`
+    let path = match Path::new(input).extension() {
+        Some(_) => PathBuf::from(input),
+        None => PathBuf::from(format!("./{}.xlsx", input)),
+    };
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+
+    match extension.as_str() {
+        "xlsx" => Ok(to_clip_rows(
+            read_xlsx_cells(&path, &config.sheet)?,
+            config.columns,
+        )),
+        "csv" => Ok(to_clip_rows(read_csv_cells(&path)?, config.columns)),
+        "txt" => read_txt_rows(&path),
+        "json" => read_json_rows(&path),
+        _ => Err(Error::Input(format!(
+            "Unsupported input format \"{}\", expected .xlsx, .csv, .txt or .json",
+            path.display()
+        ))),
+    }
+}
+
+// Lists of a start-end range and a link, the end column is left empty
+const RANGE_COLUMNS: Columns = Columns {
+    start: 0,
+    end: 2,
+    link: 1,
+    name: None,
+    format: None,
+    quality: None,
+};
+
+// Columns come from --columns, then from a recognizable header row, then from
+// a link in the second column, and otherwise default to start, end, link. A
+// first row without a usable link is taken to be a header as well.
+fn to_clip_rows(cells: Vec<Vec<String>>, columns: Option<Columns>) -> Vec<ClipRow> {
+    let header = cells.first().and_then(|row| Columns::from_header(row));
+    let cell = |row: &[String], index: usize| row.get(index).cloned().unwrap_or_default();
+    let ranges = cells
+        .iter()
+        .take(2)
+        .any(|row| VideoLink::new(&cell(row, 1)).is_ok() && cell(row, 2).trim().is_empty());
+    let columns = columns
+        .or(header)
+        .or(ranges.then_some(RANGE_COLUMNS))
+        .unwrap_or_default();
+    let has_header = header.is_some()
+        || cells
+            .first()
+            .is_some_and(|row| VideoLink::new(&cell(row, columns.link)).is_err());
+
+    cells
+        .iter()
+        .zip(1..)
+        .skip(has_header as usize)
+        .filter(|(row, _)| row.iter().any(|cell| !cell.trim().is_empty()))
+        .map(|(row, i)| ClipRow {
+            row: i,
+            start: cell(row, columns.start),
+            end: cell(row, columns.end),
+            link: cell(row, columns.link),
+            name: columns
+                .name
+                .map(|index| cell(row, index))
+                .unwrap_or_default(),
+            format: columns
+                .format
+                .map(|index| cell(row, index))
+                .unwrap_or_default(),
+            quality: columns
+                .quality
+                .map(|index| cell(row, index))
+                .unwrap_or_default(),
+        })
+        .collect()
+}
+
+fn read_xlsx_cells(path: &Path, sheet: &str) -> Result<Vec<Vec<String>>, Error> {
+    let mut workbook = open_workbook::<Xlsx<_>, _>(path)?;
+    let range: calamine::Range<Data> = workbook.worksheet_range(sheet)?;
+    let rows = range
+        .rows()
+        .map(|row| row.iter().map(cell_to_string).collect())
+        .collect();
+    Ok(rows)
+}
+
+// Time formatted cells hold a fraction of a day and whole numbers come back
+// as floats, so both are turned into something handle_time understands
+fn cell_to_string(cell: &Data) -> String {
+    match cell {
+        Data::String(value) => value.to_string(),
+        Data::DateTime(time) => format_time(time.as_f64() * 86_400.0),
+        Data::Float(value) if value.fract() == 0.0 => format!("{}", *value as i64),
+        Data::Empty => String::new(),
+        _ => cell.to_string(),
+    }
+}
+
+pub(crate) const STDIN_INPUT: &str = "-";
+
+// Stdin can only be read once but rows are read again to expand playlists
+static STDIN: Mutex<Option<String>> = Mutex::new(None);
+
+// Lines of start,end,link like a csv file
+fn read_stdin_cells() -> Result<Vec<Vec<String>>, Error> {
+    let mut stdin = STDIN.lock().unwrap();
+    if stdin.is_none() {
+        *stdin = Some(std::io::read_to_string(std::io::stdin())?);
+    }
+    let content = stdin.as_deref().unwrap_or_default();
+    Ok(content.lines().map(parse_csv_line).collect())
+}
+
+fn read_csv_cells(path: &Path) -> Result<Vec<Vec<String>>, Error> {
+    let content = fs::read_to_string(path)?;
+    Ok(content.lines().map(parse_csv_line).collect())
+}
+
+fn parse_csv_line(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => fields.push(std::mem::take(&mut field).trim().to_string()),
+            _ => field.push(c),
+        }
+    }
+    fields.push(field.trim().to_string());
+    fields
+}
+
+// Times may be given as strings or as numbers of seconds
+#[derive(serde::Deserialize)]
+struct JsonClip {
+    #[serde(alias = "link")]
+    url: String,
+    #[serde(default)]
+    start: Option<serde_json::Value>,
+    #[serde(default)]
+    end: Option<serde_json::Value>,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    format: Option<String>,
+    #[serde(default)]
+    quality: Option<String>,
+}
+
+fn read_json_rows(path: &Path) -> Result<Vec<ClipRow>, Error> {
+    let content = fs::read_to_string(path)?;
+    let clips = serde_json::from_str::<Vec<JsonClip>>(&content)
+        .map_err(|e| Error::Input(format!("Invalid JSON in {}: {}", path.display(), e)))?;
+    let time = |value: Option<serde_json::Value>| match value {
+        Some(serde_json::Value::String(time)) => time,
+        Some(serde_json::Value::Number(seconds)) => seconds.to_string(),
+        _ => String::new(),
+    };
+    let rows = clips
+        .into_iter()
+        .zip(1..)
+        .map(|(clip, i)| ClipRow {
+            row: i,
+            start: time(clip.start),
+            end: time(clip.end),
+            link: clip.url,
+            name: clip.name.unwrap_or_default(),
+            format: clip.format.unwrap_or_default(),
+            quality: clip.quality.unwrap_or_default(),
+        })
+        .collect();
+    Ok(rows)
+}
+
+fn read_txt_rows(path: &Path) -> Result<Vec<ClipRow>, Error> {
+    let content = fs::read_to_string(path)?;
+    let rows = content
+        .lines()
+        .zip(1..)
+        .map(|(line, i)| (line.trim(), i))
+        .filter(|(line, _)| !line.is_empty())
+        .map(|(line, i)| ClipRow {
+            row: i,
+            start: String::new(),
+            end: String::new(),
+            link: line.to_string(),
+            name: String::new(),
+            format: String::new(),
+            quality: String::new(),
+        })
+        .collect();
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clips(id: &str, count: usize) -> Vec<VideoLink> {
+        let link = format!("https://youtu.be/{}", id);
+        vec![VideoLink::new(&link).unwrap(); count]
+    }
+
+    #[test]
+    fn selected_clips_keep_their_number() {
+        let mut videos = IndexMap::new();
+        videos.insert("abcdefghijk".to_string(), clips("abcdefghijk", 3));
+        videos.insert("bbcdefghijk".to_string(), clips("bbcdefghijk", 2));
+        let selected = parse_selection("2, 4-5", 5).unwrap();
+        keep_selected(&mut videos, &selected);
+
+        let numbers = videos
+            .values()
+            .map(|clips| clips.iter().map(|clip| clip.number).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(numbers, [vec![Some(2)], vec![Some(1), Some(2)]]);
+
+        assert!(parse_selection("0", 5).is_err());
+        assert!(parse_selection("6", 5).is_err());
+        assert_eq!(parse_selection("", 3).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn videos_of_rows_with_bad_times_are_still_listed() {
+        let path =
+            std::env::temp_dir().join(format!("clip_downloader_listed_{}.csv", std::process::id()));
+        fs::write(
+            &path,
+            "0:10,0:20,https://youtu.be/abcdefghijk\n\
+             ab:cd,0:20,https://youtu.be/bbcdefghijk\n\
+             0:30,0:10,https://youtu.be/cbcdefghijk\n\
+             0:10,0:20,https://www.youtube.com/watch?v=dbcdefghijk&list=PLBCF2DAC6FFB574DE\n",
+        )
+        .unwrap();
+        let mut config = Config {
+            inputs: vec![path.display().to_string()],
+            ..Config::default()
+        };
+        let listed = listed_videos(&config, &HashMap::new()).unwrap();
+        let mut listed = listed.into_iter().collect::<Vec<_>>();
+        listed.sort();
+        assert_eq!(
+            listed,
+            ["abcdefghijk", "bbcdefghijk", "cbcdefghijk", "dbcdefghijk"]
+        );
+
+        // A playlist that could not be listed must not clear its videos
+        config.expand_playlists = true;
+        assert!(listed_videos(&config, &HashMap::new()).is_err());
+        let playlists = HashMap::from([(
+            "PLBCF2DAC6FFB574DE".to_string(),
+            vec!["ebcdefghijk".to_string()],
+        )]);
+        let listed = listed_videos(&config, &playlists).unwrap();
+        assert!(listed.contains("ebcdefghijk") && !listed.contains("dbcdefghijk"));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,429 @@
+use config::{SPEED_WARN_MAX, SPEED_WARN_MIN};
+use download::download_size;
+use events::text_output;
+use indexmap::IndexMap;
+use log::{error, info, warn};
+use progress::format_bytes;
+use std::{collections::HashMap, io::Write, path::PathBuf, sync::Arc};
+use tokio::{
+    sync::{mpsc, Mutex, Semaphore},
+    task::JoinSet,
+};
+use youtube_dl::SingleVideo;
+
+mod archive;
+mod cache;
+mod clip;
+mod config;
+mod download;
+mod error;
+mod events;
+mod input;
+mod partial;
+mod progress;
+mod report;
+mod resume;
+mod video;
+
+pub use cache::{clean_cache, evict_cache};
+pub use clip::{
+    clip_name, clip_output_path, clip_video, highlights_path, process_video, sanitize_file_name,
+    video_dir, whole_output_path,
+};
+pub use config::{
+    Columns, Config, Container, Corner, Crop, HwAccel, OutputFormat, OverlapMode, VideoCodec,
+};
+pub use download::{
+    check_cache, check_folder, check_hwaccel, download_video, expand_playlists, list_formats,
+    prefetch_metadata, probe, setup, setup_yt_dlp,
+};
+pub use error::Error;
+pub use events::Event;
+pub use input::{listed_videos, organize_videos, read_rows, select_clips, ClipRow};
+pub use partial::remove_partials;
+pub use progress::{init_logger, Progress, ProgressBar};
+pub use report::{ClipResult, Report, SkippedVideo};
+pub use video::{
+    format_duration, format_time, handle_link, handle_time, link_timestamp, playlist_id,
+    time_to_seconds, Video, VideoLink,
+};
+
+pub async fn run(mut config: Config) -> Result<(), Error> {
+    events::enable_events(config.json_events);
+    let videos = Mutex::new(IndexMap::<String, Vec<VideoLink>>::new());
+    let mut downloaded_videos = HashMap::<String, PathBuf>::new();
+    let mut id_videos = Vec::<String>::new();
+    let mut skipped = Vec::<(String, String)>::new();
+    let mut results = Vec::<ClipResult>::new();
+    let (mut downloaded, mut cached) = (0, 0);
+
+    if let Some(link) = &config.list_formats {
+        return list_formats(link, &config).await;
+    }
+
+    if config.probe_only {
+        return probe(&config).await;
+    }
+
+    if config.dry_run {
+        check_cache(&config.cache_dir, &mut downloaded_videos, false);
+        if config.expand_playlists {
+            warn!("Playlists are only expanded in a real run");
+        }
+        organize_videos(&config, &HashMap::new(), &mut *videos.lock().await)?;
+        print_plan(&*videos.lock().await, &downloaded_videos, &config)?;
+        return Ok(());
+    }
+
+    if config.clean_cache {
+        let playlists = match config.expand_playlists {
+            true => expand_playlists(&config, &setup_yt_dlp(&config).await?).await?,
+            false => HashMap::new(),
+        };
+        clean_cache(&config.cache_dir, &listed_videos(&config, &playlists)?)?;
+        return Ok(());
+    }
+
+    if !(SPEED_WARN_MIN..=SPEED_WARN_MAX).contains(&config.speed) {
+        warn!(
+            "A speed of {} chains several atempo filters, audio quality will suffer",
+            config.speed
+        );
+    }
+    if config.loop_count > 1 && (config.reverse || config.fade > 0.0) {
+        warn!("--loop repeats clips after --reverse and --fade, so every repeat has them");
+    }
+    let limit = Arc::new(Semaphore::new(config.threads));
+    let mut tasks = JoinSet::new();
+    let progress = Progress::new(config.quiet || config.json_events);
+    let yt_dlp_path = setup(&config).await?;
+    if config.hwaccel.is_some() && !check_hwaccel(&config) {
+        warn!("The hardware encoder does not start, falling back to software encoding");
+        config.hwaccel = None;
+    }
+    check_cache(&config.cache_dir, &mut downloaded_videos, true);
+
+    let playlists = match config.expand_playlists {
+        true => expand_playlists(&config, &yt_dlp_path).await?,
+        false => HashMap::new(),
+    };
+    organize_videos(&config, &playlists, &mut *videos.lock().await)?;
+    if config.select {
+        select_clips(&mut *videos.lock().await)?;
+    }
+    resume::load_resume(&config);
+    let videos_list = videos.lock().await.clone();
+
+    // Make sure all the downloaded videos are processed first
+    for (id, _) in videos_list.iter() {
+        if downloaded_videos.contains_key(id) {
+            id_videos.push(id.clone());
+        }
+    }
+
+    for (id, _) in videos_list.iter() {
+        if !downloaded_videos.contains_key(id) {
+            id_videos.push(id.clone());
+        }
+    }
+
+    let qualities = video_qualities(&videos_list);
+    let mut metadata = prefetch_metadata(
+        &id_videos,
+        &downloaded_videos,
+        &qualities,
+        &yt_dlp_path,
+        &config,
+    )
+    .await;
+    id_videos.retain(|id| match metadata.get(id) {
+        Some(Ok(_)) => true,
+        Some(Err(e)) => {
+            error!("Failed to get video {}: {}", id, e);
+            skipped.push((id.clone(), e.reason()));
+            false
+        }
+        None => {
+            skipped.push((id.clone(), "its details could not be fetched".to_string()));
+            false
+        }
+    });
+    print_summary(&id_videos, &metadata, &videos_list, &downloaded_videos);
+    if config.title_folders {
+        config.title_dirs = clip::title_folders(videos_list.keys().filter_map(|id| {
+            let Some(Ok(data)) = metadata.get(id) else {
+                return None;
+            };
+            Some((
+                id.clone(),
+                clip::title_name(id, data.title.as_deref(), &config),
+            ))
+        }));
+    }
+
+    // Downloaders start in list order, the semaphore being fair, and hand each
+    // video over as soon as it is ready, so its clips are cut while the next
+    // ones download
+    let download_limit = Arc::new(Semaphore::new(config.download_threads));
+    let (ready, mut videos_ready) = mpsc::unbounded_channel();
+    for id in id_videos.iter() {
+        let id = id.clone();
+        let Some(Ok(data)) = metadata.remove(&id) else {
+            continue;
+        };
+        let cache = downloaded_videos.clone();
+        let yt_dlp_path = yt_dlp_path.clone();
+        let config = match qualities.get(&id) {
+            Some(quality) => config.with_quality(quality),
+            None => config.clone(),
+        };
+        let progress = progress.clone();
+        let limit = download_limit.clone();
+        let ready = ready.clone();
+        tokio::spawn(async move {
+            let _permit = limit.acquire_owned().await.ok();
+            let result =
+                download_video(id.clone(), data, cache, yt_dlp_path, &config, &progress).await;
+            let _ = ready.send((id, result));
+        });
+    }
+    drop(ready);
+
+    while let Some((id, result)) = videos_ready.recv().await {
+        let video = match result {
+            Ok(video) => video,
+            Err(e) => {
+                error!("Failed to get video {}: {}", id, e);
+                skipped.push((id.clone(), e.reason()));
+                continue;
+            }
+        };
+        match downloaded_videos.contains_key(&id) {
+            true => cached += 1,
+            false => downloaded += 1,
+        }
+        info!("Processing video: {}", video.title());
+        let mut clip_ref = videos_list.get(&id).unwrap().clone();
+        if config.by_chapters {
+            match video.chapter_clips() {
+                Some(clips) => clip_ref = clips,
+                None if clip_ref.is_empty() => {
+                    warn!("Skipping video {}: it has no chapters", video.title());
+                    continue;
+                }
+                None => warn!(
+                    "Video {} has no chapters, using the clips from the input",
+                    video.title()
+                ),
+            }
+        }
+        // Only rows without a range are split, the name and format of the
+        // row carry over to every segment
+        if let Some(length) = config
+            .segment
+            .filter(|_| clip_ref.iter().all(|c| c.is_whole()))
+        {
+            match video.segment_clips(length) {
+                Some(mut clips) => {
+                    if let Some(row) = clip_ref.first() {
+                        for (clip, n) in clips.iter_mut().zip(1..) {
+                            clip.format = row.format;
+                            clip.name = row.name.as_ref().map(|name| format!("{} [{}]", name, n));
+                        }
+                    }
+                    info!(
+                        "Splitting video {} into {} segment(s)",
+                        video.title(),
+                        clips.len()
+                    );
+                    clip_ref = clips;
+                }
+                None => warn!(
+                    "Video {} has no known duration, keeping it whole",
+                    video.title()
+                ),
+            }
+        }
+        // Clips that never reach ffmpeg come back right away
+        let rejected = process_video(video, clip_ref, &config, &limit, &mut tasks, &progress);
+        for clip in rejected.iter() {
+            events::emit(Event::ClipDone(clip.clone()));
+        }
+        results.extend(rejected);
+    }
+
+    while let Some(result) = tasks.join_next().await {
+        match result {
+            Ok(clips) => results.extend(clips),
+            Err(e) => error!("Clip task failed: {}", e),
+        }
+    }
+    results.sort_by_key(|clip| (videos_list.get_index_of(&clip.video_id), clip.index));
+    let report = Report::new(results, downloaded, cached, skipped);
+    for (title, count, duration) in report.subtotals() {
+        info!(
+            "  {}: {} clip(s), {}",
+            title,
+            count,
+            format_duration(duration)
+        );
+    }
+    info!(
+        "Extracted {} clip(s) totaling {}",
+        report.succeeded,
+        format_duration(report.duration)
+    );
+    if report.failed > 0 {
+        error!("{} clip(s) failed", report.failed);
+    }
+
+    if !report.skipped.is_empty() {
+        warn!("Skipped {} video(s):", report.skipped.len());
+        for video in report.skipped.iter() {
+            warn!("  {}: {}", video.id, video.reason);
+        }
+    }
+    let path = report.write(&config)?;
+    info!("Report written to {}", path.display());
+    let path = report.write_playlist(&config)?;
+    info!("Playlist written to {}", path.display());
+    if config.edl {
+        let path = report.write_edl(&config)?;
+        info!("EDL written to {}", path.display());
+    }
+    // Anything left to retry keeps the resume file for the next run
+    match report.failed == 0 && report.skipped.is_empty() {
+        true => resume::remove_resume(),
+        false => info!("Clips finished so far are recorded, rerun to pick up the rest"),
+    }
+    if let Some(archive) = &config.archive {
+        let files = archive::write_archive(archive, &config)?;
+        info!("Archived {} file(s) to {}", files.len(), archive.display());
+        if config.archive_only {
+            archive::remove_archived(&files, &config);
+        }
+    }
+    if let Some(max_size) = config.max_cache_size {
+        evict_cache(&config.cache_dir, max_size)?;
+    }
+
+    Ok(())
+}
+
+// The quality column applies to the whole video, the first row giving one wins
+fn video_qualities(videos: &IndexMap<String, Vec<VideoLink>>) -> HashMap<String, String> {
+    let mut qualities = HashMap::new();
+    for (id, clips) in videos.iter() {
+        let mut given = clips.iter().filter_map(|clip| clip.quality.as_ref());
+        let Some(quality) = given.next() else {
+            continue;
+        };
+        if given.any(|other| other != quality) {
+            warn!(
+                "Video {} has rows with different qualities, using {}",
+                id, quality
+            );
+        }
+        qualities.insert(id.clone(), quality.clone());
+    }
+    qualities
+}
+
+// Sums up the run once every video is known to be available
+fn print_summary(
+    ids: &[String],
+    metadata: &HashMap<String, Result<SingleVideo, Error>>,
+    videos: &IndexMap<String, Vec<VideoLink>>,
+    downloaded: &HashMap<String, PathBuf>,
+) {
+    let clips = ids
+        .iter()
+        .filter_map(|id| videos.get(id))
+        .map(|clips| clips.len())
+        .sum::<usize>();
+    let to_download = ids
+        .iter()
+        .filter(|id| !downloaded.contains_key(*id))
+        .collect::<Vec<_>>();
+    let size = to_download
+        .iter()
+        .filter_map(|id| metadata.get(*id)?.as_ref().ok())
+        .filter_map(download_size)
+        .sum::<u64>();
+    info!(
+        "{} video(s) with {} clip(s), {} to download (about {}), {} cached",
+        ids.len(),
+        clips,
+        to_download.len(),
+        format_bytes(size),
+        ids.len() - to_download.len()
+    );
+    let unavailable = metadata.values().filter(|result| result.is_err()).count();
+    if unavailable > 0 {
+        warn!(
+            "{} video(s) are unavailable and will be skipped",
+            unavailable
+        );
+    }
+}
+
+fn print_plan(
+    videos: &IndexMap<String, Vec<VideoLink>>,
+    downloaded: &HashMap<String, PathBuf>,
+    config: &Config,
+) -> Result<(), Error> {
+    // Titles are not fetched in a dry run, only the ones saved next to cached
+    // videos are known
+    let titles = videos
+        .keys()
+        .map(|id| {
+            downloaded
+                .contains_key(id)
+                .then(|| download::read_info(&config.cache_dir, id))
+                .flatten()
+                .and_then(|data| data.title)
+        })
+        .collect::<Vec<_>>();
+    let names = videos
+        .keys()
+        .zip(titles.iter())
+        .map(|(id, title)| (id.clone(), clip::title_name(id, title.as_deref(), config)));
+    let config = &Config {
+        title_dirs: clip::title_folders(names),
+        ..config.clone()
+    };
+    let mut out = text_output();
+    let mut provisional = false;
+    for ((id, clips), title) in videos.iter().zip(titles) {
+        match downloaded.get(id) {
+            Some(path) => writeln!(out, "Video {}: cached at {}", id, path.display())?,
+            None => writeln!(out, "Video {}: will be downloaded", id)?,
+        }
+        let name = clip::title_name(id, title.as_deref(), config);
+        let unknown = title.is_none() && !config.id_names;
+        let mut clips = clips.clone();
+        clip::rename_collisions(&name, &mut clips, config);
+        for (clip, i) in clips.iter().zip(1..) {
+            let i = clip.number.unwrap_or(i);
+            // Only names and folders taken from the title change
+            let marked = unknown && (clip.name.is_none() || config.title_folders);
+            provisional |= marked;
+            writeln!(
+                out,
+                "  Clip #{}: {} -> {} => {}{}",
+                i,
+                clip.start_time,
+                clip.end_time.as_deref().unwrap_or("END"),
+                clip_output_path(i, clip, &name, config).display(),
+                if marked { " *" } else { "" }
+            )?;
+        }
+    }
+    if provisional {
+        writeln!(
+            out,
+            "* The title of the video is not known yet, the id stands in for it"
+        )?;
+    }
+    Ok(())
+}
@@ -0,0 +1,77 @@
+use log::info;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+// Files that are being written right now, so an interrupted run can remove
+// them instead of leaving truncated videos or clips behind
+enum Partial {
+    // yt-dlp picks the file names, so everything in the cache starting with
+    // the id of a video that was not cached yet belongs to the download
+    Download { dir: PathBuf, id: String },
+    File(PathBuf),
+}
+
+static PARTIALS: Mutex<Vec<(usize, Partial)>> = Mutex::new(Vec::new());
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+// Unregisters the file once the write is done, or was abandoned
+pub(crate) struct PartialGuard(usize);
+
+impl Drop for PartialGuard {
+    fn drop(&mut self) {
+        if let Ok(mut partials) = PARTIALS.lock() {
+            partials.retain(|(id, _)| *id != self.0);
+        }
+    }
+}
+
+fn register(partial: Partial) -> PartialGuard {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    if let Ok(mut partials) = PARTIALS.lock() {
+        partials.push((id, partial));
+    }
+    PartialGuard(id)
+}
+
+pub(crate) fn track_download(dir: &Path, id: &str) -> PartialGuard {
+    register(Partial::Download {
+        dir: dir.to_path_buf(),
+        id: id.to_string(),
+    })
+}
+
+pub(crate) fn track_file(path: &Path) -> PartialGuard {
+    register(Partial::File(path.to_path_buf()))
+}
+
+// Meant for a Ctrl-C handler, removes every file still being written
+pub fn remove_partials() {
+    let Ok(partials) = PARTIALS.lock() else {
+        return;
+    };
+    for (_, partial) in partials.iter() {
+        match partial {
+            Partial::Download { dir, id } => {
+                let prefix = format!("{}.", id);
+                for entry in fs::read_dir(dir).into_iter().flatten().flatten() {
+                    if entry.file_name().to_string_lossy().starts_with(&prefix) {
+                        remove(&entry.path());
+                    }
+                }
+            }
+            Partial::File(path) => remove(path),
+        }
+    }
+}
+
+fn remove(path: &Path) {
+    if fs::remove_file(path).is_ok() {
+        info!("Removed partial file {}", path.display());
+    }
+}
@@ -0,0 +1,198 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::{
+    io::{IsTerminal, Write},
+    sync::{Arc, Mutex},
+};
+
+use crate::events::{emit, events_enabled, Event};
+
+#[derive(Default)]
+struct Bars {
+    next_id: usize,
+    active: Vec<BarState>,
+    drawn: usize,
+}
+
+struct BarState {
+    id: usize,
+    label: String,
+    position: u64,
+    total: u64,
+    bytes: bool,
+}
+
+// Bars of the latest Progress, which log messages are printed above
+static BARS: Mutex<Option<Arc<Mutex<Bars>>>> = Mutex::new(None);
+
+// Coordinates output from concurrent workers: log messages are printed above
+// the active bars, which are redrawn in place after every update
+#[derive(Clone)]
+pub struct Progress {
+    bars: Option<Arc<Mutex<Bars>>>,
+}
+
+#[derive(Clone)]
+pub struct ProgressBar {
+    id: usize,
+    bars: Option<Arc<Mutex<Bars>>>,
+}
+
+impl Progress {
+    pub fn new(quiet: bool) -> Self {
+        let enabled = !quiet && std::io::stderr().is_terminal();
+        let bars = enabled.then(|| Arc::new(Mutex::new(Bars::default())));
+        if let Ok(mut current) = BARS.lock() {
+            current.clone_from(&bars);
+        }
+        Self { bars }
+    }
+    pub fn add_bar(&self, label: String, total: u64, bytes: bool) -> ProgressBar {
+        let id = match &self.bars {
+            Some(bars) => {
+                let mut bars = bars.lock().unwrap();
+                let id = bars.next_id;
+                bars.next_id += 1;
+                bars.active.push(BarState {
+                    id,
+                    label,
+                    position: 0,
+                    total,
+                    bytes,
+                });
+                bars.redraw();
+                id
+            }
+            None => 0,
+        };
+        ProgressBar {
+            id,
+            bars: self.bars.clone(),
+        }
+    }
+}
+
+impl ProgressBar {
+    pub fn set_position(&self, position: u64) {
+        self.update(|bar| bar.position = position);
+    }
+    // The bar is removed once it reaches its total
+    pub fn inc(&self, delta: u64) {
+        self.update(|bar| bar.position += delta);
+    }
+    pub fn finish(&self) {
+        if let Some(bars) = &self.bars {
+            let mut bars = bars.lock().unwrap();
+            bars.active.retain(|bar| bar.id != self.id);
+            bars.redraw();
+        }
+    }
+    fn update(&self, f: impl FnOnce(&mut BarState)) {
+        if let Some(bars) = &self.bars {
+            let mut bars = bars.lock().unwrap();
+            if let Some(bar) = bars.active.iter_mut().find(|bar| bar.id == self.id) {
+                f(bar);
+            }
+            bars.active
+                .retain(|bar| bar.bytes || bar.total == 0 || bar.position < bar.total);
+            bars.redraw();
+        }
+    }
+}
+
+impl Bars {
+    fn clear(&mut self) {
+        let mut stderr = std::io::stderr().lock();
+        for _ in 0..self.drawn {
+            let _ = write!(stderr, "\x1b[1A\x1b[2K");
+        }
+        self.drawn = 0;
+    }
+    fn redraw(&mut self) {
+        self.clear();
+        let mut stderr = std::io::stderr().lock();
+        for bar in self.active.iter() {
+            let _ = writeln!(stderr, "{}", bar.render());
+        }
+        self.drawn = self.active.len();
+    }
+    fn print_above(&mut self, message: &str) {
+        self.clear();
+        eprintln!("{}", message);
+        self.redraw();
+    }
+}
+
+impl BarState {
+    fn render(&self) -> String {
+        const WIDTH: u64 = 30;
+        let filled = match self.total {
+            0 => 0,
+            total => (self.position.min(total) * WIDTH / total) as usize,
+        };
+        let bar = format!(
+            "{}{}",
+            "#".repeat(filled),
+            "-".repeat(WIDTH as usize - filled)
+        );
+        let count = match (self.bytes, self.total) {
+            (true, 0) => format_bytes(self.position),
+            (true, total) => format!("{}/{}", format_bytes(self.position), format_bytes(total)),
+            (false, total) => format!("{}/{}", self.position, total),
+        };
+        format!("{} [{}] {}", self.label, bar, count)
+    }
+}
+
+// Info goes to stdout and everything louder to stderr, unless bars are drawn
+// on stderr, in which case all of it is printed above them
+struct Logger {
+    level: LevelFilter,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        // Only errors are kept in JSON mode, as events, so stdout stays
+        // parseable. Warnings still go to stderr.
+        if events_enabled() {
+            match record.level() {
+                Level::Error => emit(Event::Error {
+                    message: record.args().to_string(),
+                }),
+                Level::Warn => eprintln!("Warning: {}", record.args()),
+                _ => {}
+            }
+            return;
+        }
+        let message = match record.level() {
+            Level::Info => record.args().to_string(),
+            Level::Warn => format!("Warning: {}", record.args()),
+            Level::Error => format!("Error: {}", record.args()),
+            Level::Debug | Level::Trace => format!("Debug: {}", record.args()),
+        };
+        let bars = BARS.lock().ok().and_then(|bars| bars.clone());
+        match bars {
+            Some(bars) => bars.lock().unwrap().print_above(&message),
+            None if record.level() == Level::Info => println!("{}", message),
+            None => eprintln!("{}", message),
+        }
+    }
+    fn flush(&self) {}
+}
+
+// Installs the logger the CLI prints through, a library user can install
+// their own `log` implementation instead
+pub fn init_logger(level: LevelFilter) {
+    if log::set_boxed_logger(Box::new(Logger { level })).is_ok() {
+        log::set_max_level(level);
+    }
+}
+
+pub(crate) fn format_bytes(bytes: u64) -> String {
+    format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0))
+}
@@ -0,0 +1,171 @@
+use serde::Serialize;
+use std::{fs, path::PathBuf};
+
+use crate::{time_to_seconds, Config, Error, Video, VideoLink};
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ClipResult {
+    pub video_id: String,
+    pub title: String,
+    pub index: i32,
+    pub start: String,
+    pub end: Option<String>,
+    pub output: PathBuf,
+    // The cached video the clip was cut from
+    pub source: PathBuf,
+    pub duration: Option<f64>,
+    // Whether ffprobe found the clip sound, None without --verify
+    pub verified: Option<bool>,
+    pub success: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct SkippedVideo {
+    pub id: String,
+    pub reason: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Report {
+    pub downloaded: usize,
+    pub cached: usize,
+    pub succeeded: usize,
+    pub failed: usize,
+    // Seconds of footage in the succeeded clips
+    pub duration: f64,
+    pub skipped: Vec<SkippedVideo>,
+    pub clips: Vec<ClipResult>,
+}
+
+impl ClipResult {
+    pub fn new(
+        video: &Video,
+        index: i32,
+        clip: &VideoLink,
+        output: PathBuf,
+        duration: Option<f64>,
+        result: &Result<(), Error>,
+    ) -> Self {
+        Self {
+            video_id: video.id.clone(),
+            title: video.title(),
+            index,
+            start: clip.start_time.clone(),
+            end: clip.end_time.clone(),
+            output,
+            source: video.path.clone(),
+            duration,
+            verified: None,
+            success: result.is_ok(),
+            error: result.as_ref().err().map(|e| e.to_string()),
+        }
+    }
+}
+
+impl Report {
+    // Clips are kept in the order given
+    pub fn new(
+        clips: Vec<ClipResult>,
+        downloaded: usize,
+        cached: usize,
+        skipped: Vec<(String, String)>,
+    ) -> Self {
+        let succeeded = clips.iter().filter(|clip| clip.success).count();
+        let duration = clips
+            .iter()
+            .filter(|clip| clip.success)
+            .filter_map(|clip| clip.duration)
+            .sum();
+        Self {
+            downloaded,
+            cached,
+            succeeded,
+            failed: clips.len() - succeeded,
+            duration,
+            skipped: skipped
+                .into_iter()
+                .map(|(id, reason)| SkippedVideo { id, reason })
+                .collect(),
+            clips,
+        }
+    }
+    // Clip count and seconds of footage of the succeeded clips per video
+    pub fn subtotals(&self) -> Vec<(&str, usize, f64)> {
+        let mut subtotals = Vec::<(&str, &str, usize, f64)>::new();
+        for clip in self.clips.iter().filter(|clip| clip.success) {
+            match subtotals.last_mut() {
+                Some((id, _, count, duration)) if *id == clip.video_id => {
+                    *count += 1;
+                    *duration += clip.duration.unwrap_or(0.0);
+                }
+                _ => subtotals.push((&clip.video_id, &clip.title, 1, clip.duration.unwrap_or(0.0))),
+            }
+        }
+        subtotals
+            .into_iter()
+            .map(|(_, title, count, duration)| (title, count, duration))
+            .collect()
+    }
+    pub fn write(&self, config: &Config) -> Result<PathBuf, Error> {
+        let path = config
+            .report
+            .clone()
+            .unwrap_or_else(|| config.output_dir.join("report.json"));
+        let json = serde_json::to_string_pretty(self)
+            .map_err(|e| Error::Input(format!("Failed to serialize the report: {}", e)))?;
+        fs::write(&path, json)?;
+        Ok(path)
+    }
+    // Succeeded clips as an m3u playlist in the output directory, a joined
+    // highlights file is listed once
+    pub fn write_playlist(&self, config: &Config) -> Result<PathBuf, Error> {
+        let mut playlist = String::from("#EXTM3U\n");
+        let mut last = None;
+        for clip in self.clips.iter().filter(|clip| clip.success) {
+            if last.replace(&clip.output) == Some(&clip.output) {
+                continue;
+            }
+            let path = clip
+                .output
+                .strip_prefix(&config.output_dir)
+                .unwrap_or(&clip.output);
+            let duration = clip.duration.map_or(-1, |duration| duration.round() as i64);
+            playlist.push_str(&format!(
+                "#EXTINF:{},{} [{}]\n{}\n",
+                duration,
+                clip.title,
+                clip.index,
+                path.display()
+            ));
+        }
+        let path = config.output_dir.join("clips.m3u");
+        fs::write(&path, playlist)?;
+        Ok(path)
+    }
+    // An mpv EDL playing the clip ranges straight from the cached videos,
+    // paths are length prefixed so commas in them are safe
+    pub fn write_edl(&self, config: &Config) -> Result<PathBuf, Error> {
+        let mut edl = String::from("# mpv EDL v0\n");
+        for clip in self.clips.iter().filter(|clip| clip.success) {
+            let source = fs::canonicalize(&clip.source).unwrap_or(clip.source.clone());
+            let source = source.to_string_lossy();
+            let start = time_to_seconds(&clip.start).unwrap_or(0.0);
+            let length = clip
+                .end
+                .as_deref()
+                .and_then(time_to_seconds)
+                .map_or(String::new(), |end| format!(",{}", end - start));
+            edl.push_str(&format!(
+                "%{}%{},{}{}\n",
+                source.len(),
+                source,
+                start,
+                length
+            ));
+        }
+        let path = config.output_dir.join("clips.edl");
+        fs::write(&path, edl)?;
+        Ok(path)
+    }
+}
@@ -0,0 +1,103 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::{Config, VideoLink};
+
+pub(crate) const RESUME_FILE: &str = ".progress.json";
+
+// A clip that was cut in full, recorded so a restarted run does not cut it
+// again. The output path is part of it, so changing the format or the name
+// of a clip makes it count as new
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct FinishedClip {
+    video_id: String,
+    start: String,
+    end: Option<String>,
+    output: PathBuf,
+}
+
+impl FinishedClip {
+    pub(crate) fn new(clip: &VideoLink, output: &Path) -> Self {
+        Self {
+            video_id: clip.id.clone(),
+            start: clip.start_time.clone(),
+            end: clip.end_time.clone(),
+            output: output.to_path_buf(),
+        }
+    }
+}
+
+struct Resume {
+    path: PathBuf,
+    clips: Vec<FinishedClip>,
+}
+
+static RESUME: Mutex<Option<Resume>> = Mutex::new(None);
+
+// Picks up the clips an interrupted run already finished
+pub(crate) fn load_resume(config: &Config) {
+    let path = config.output_dir.join(RESUME_FILE);
+    let clips = match fs::read_to_string(&path) {
+        Ok(json) => serde_json::from_str::<Vec<FinishedClip>>(&json).unwrap_or_else(|e| {
+            warn!("Ignoring {}: {}", path.display(), e);
+            Vec::new()
+        }),
+        Err(_) => Vec::new(),
+    };
+    if !clips.is_empty() {
+        info!(
+            "Resuming, {} clip(s) were finished in an earlier run",
+            clips.len()
+        );
+    }
+    if let Ok(mut resume) = RESUME.lock() {
+        *resume = Some(Resume { path, clips });
+    }
+}
+
+pub(crate) fn is_finished(clip: &FinishedClip) -> bool {
+    match RESUME.lock() {
+        Ok(resume) => resume
+            .as_ref()
+            .is_some_and(|resume| resume.clips.contains(clip)),
+        Err(_) => false,
+    }
+}
+
+// The file is rewritten after every clip, so a crash loses at most the clips
+// that were being cut at the time
+pub(crate) fn mark_finished(clip: FinishedClip) {
+    let Ok(mut resume) = RESUME.lock() else {
+        return;
+    };
+    let Some(resume) = resume.as_mut() else {
+        return;
+    };
+    if resume.clips.contains(&clip) {
+        return;
+    }
+    resume.clips.push(clip);
+    let temp = resume.path.with_extension("json.tmp");
+    let written = serde_json::to_string_pretty(&resume.clips)
+        .map_err(std::io::Error::from)
+        .and_then(|json| fs::write(&temp, json))
+        .and_then(|_| fs::rename(&temp, &resume.path));
+    if let Err(e) = written {
+        warn!("Could not update {}: {}", resume.path.display(), e);
+    }
+}
+
+// Called once every clip made it, the next run starts from scratch
+pub(crate) fn remove_resume() {
+    let Ok(mut resume) = RESUME.lock() else {
+        return;
+    };
+    if let Some(resume) = resume.take() {
+        let _ = fs::remove_file(&resume.path);
+    }
+}
@@ -0,0 +1,469 @@
+use std::path::PathBuf;
+
+use youtube_dl::SingleVideo;
+
+use crate::{Error, OutputFormat};
+
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct VideoLink {
+    pub id: String,
+    pub start_time: String,
+    // None runs the clip to the end of the video
+    pub end_time: Option<String>,
+    // Label from the name column, used as the clip file name when set
+    pub name: Option<String>,
+    // Format from the format column, overriding --format for this clip
+    #[serde(skip)]
+    pub format: Option<OutputFormat>,
+    // yt-dlp format selector from the quality column, overriding --quality
+    // for the whole video
+    #[serde(skip)]
+    pub quality: Option<String>,
+    // Position among the clips of the video when --select left some out,
+    // so an unnamed clip keeps the [N] a full run gives it
+    #[serde(skip)]
+    pub number: Option<i32>,
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct Video {
+    pub id: String,
+    pub path: PathBuf,
+    pub data: SingleVideo,
+    // Subtitles fetched for --subs, None when the language was not available
+    pub subtitles: Option<PathBuf>,
+}
+
+impl VideoLink {
+    pub fn new(link: &str) -> Result<Self, Error> {
+        let id = video_id(link.trim()).ok_or_else(|| Error::InvalidLink(link.to_string()))?;
+        Ok(Self {
+            id: id.to_string(),
+            start_time: "00:00".to_string(),
+            end_time: Some("00:00".to_string()),
+            name: None,
+            format: None,
+            quality: None,
+            number: None,
+        })
+    }
+    pub fn set_start_time(&mut self, time: &str) -> Result<(), Error> {
+        self.start_time = handle_time(time)?;
+        Ok(())
+    }
+    pub fn set_end_time(&mut self, time: &str) -> Result<(), Error> {
+        self.end_time = match time.trim().eq_ignore_ascii_case("end") {
+            true => None,
+            false => Some(handle_time(time)?),
+        };
+        Ok(())
+    }
+    pub fn is_whole(&self) -> bool {
+        self.end_time.is_none() && time_to_seconds(&self.start_time) == Some(0.0)
+    }
+    pub fn duration(&self) -> Option<f64> {
+        if self.is_relative() {
+            return None;
+        }
+        Some(time_to_seconds(self.end_time.as_ref()?)? - time_to_seconds(&self.start_time)?)
+    }
+    pub fn is_relative(&self) -> bool {
+        is_relative(&self.start_time) || self.end_time.as_deref().is_some_and(is_relative)
+    }
+    // Percentages and times from the end only become times once the length
+    // of the video is known
+    pub fn resolve_times(&mut self, duration: Option<f64>) -> Result<(), Error> {
+        self.start_time = resolve_time(&self.start_time, duration)?;
+        if let Some(end_time) = &self.end_time {
+            self.end_time = Some(resolve_time(end_time, duration)?);
+        }
+        if self.duration().is_some_and(|duration| duration <= 0.0) {
+            return Err(Error::Input(format!(
+                "end {} is not after start {}",
+                self.end_time.as_deref().unwrap_or_default(),
+                self.start_time
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn is_relative(time: &str) -> bool {
+    time.ends_with('%') || time.starts_with('-')
+}
+
+fn resolve_time(time: &str, duration: Option<f64>) -> Result<String, Error> {
+    if !is_relative(time) {
+        return Ok(time.to_string());
+    }
+    let duration = duration.ok_or_else(|| {
+        Error::Input(format!(
+            "{} needs the length of the video, which is unknown",
+            time
+        ))
+    })?;
+    let seconds = match time.strip_suffix('%') {
+        Some(percent) => duration * percent.parse::<f64>().unwrap_or(0.0) / 100.0,
+        // Going back further than the video is long starts it from the top
+        None => (duration - time_to_seconds(&time[1..]).unwrap_or(0.0)).max(0.0),
+    };
+    Ok(format_time(seconds))
+}
+
+impl Video {
+    pub fn new(id: String, path: PathBuf, data: SingleVideo) -> Self {
+        Self {
+            id,
+            path,
+            data,
+            subtitles: None,
+        }
+    }
+    pub fn title(&self) -> String {
+        self.data.title.clone().unwrap_or_else(|| self.id.clone())
+    }
+    // One clip per chapter, None when yt-dlp found no chapters
+    pub fn chapter_clips(&self) -> Option<Vec<VideoLink>> {
+        let chapters = self.data.chapters.as_ref().filter(|c| !c.is_empty())?;
+        let clips = chapters
+            .iter()
+            .map(|chapter| VideoLink {
+                id: self.id.clone(),
+                start_time: format_time(chapter.start_time.unwrap_or(0.0)),
+                end_time: chapter.end_time.map(format_time),
+                name: chapter.title.clone(),
+                format: None,
+                quality: None,
+                number: None,
+            })
+            .collect();
+        Some(clips)
+    }
+    // Back to back clips of the given length covering the whole video, the
+    // last one running to the end. None when the duration is unknown.
+    pub fn segment_clips(&self, length: f64) -> Option<Vec<VideoLink>> {
+        let duration = self.duration().filter(|duration| *duration > 0.0)?;
+        let mut clips = Vec::new();
+        let mut start = 0.0;
+        while start < duration {
+            let end = start + length;
+            clips.push(VideoLink {
+                id: self.id.clone(),
+                start_time: format_time(start),
+                end_time: (end < duration).then(|| format_time(end)),
+                name: None,
+                format: None,
+                quality: None,
+                number: None,
+            });
+            start = end;
+        }
+        Some(clips)
+    }
+    pub fn duration(&self) -> Option<f64> {
+        self.data.duration.as_ref()?.as_f64()
+    }
+}
+
+pub fn handle_link(link: &str) -> String {
+    match video_id(link) {
+        Some(id) => format!("https://www.youtube.com/watch?v={}", id),
+        None => link.to_string(),
+    }
+}
+
+// The id of a link to a single YouTube video: watch, youtu.be, shorts, live
+// and embed links. Other sites, channels and playlists give None
+fn video_id(link: &str) -> Option<&str> {
+    let rest = link
+        .strip_prefix("https://")
+        .or_else(|| link.strip_prefix("http://"))
+        .unwrap_or(link);
+    let (host, path) = rest.split_once('/')?;
+    let id = match host {
+        "youtu.be" => path,
+        "youtube.com" | "www.youtube.com" | "m.youtube.com" | "music.youtube.com" => {
+            match path.strip_prefix("watch?") {
+                Some(query) => query.split('&').find_map(|pair| pair.strip_prefix("v="))?,
+                None => ["shorts/", "live/", "embed/"]
+                    .iter()
+                    .find_map(|prefix| path.strip_prefix(prefix))?,
+            }
+        }
+        _ => return None,
+    };
+    let id = id.split(['?', '&', '#', '/']).next()?;
+    let valid = id.len() == 11
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    valid.then_some(id)
+}
+
+// The list id of playlist links, including watch links opened from a playlist
+pub fn playlist_id(link: &str) -> Option<String> {
+    let index = link.find("list=")?;
+    let id = link[index + "list=".len()..]
+        .split(['&', '#'])
+        .next()
+        .unwrap_or_default();
+    (!id.is_empty()).then(|| id.to_string())
+}
+
+// The t= timestamp of a shared link, e.g. ?t=90 or &t=1m30s, as hh:mm:ss
+pub fn link_timestamp(link: &str) -> Option<String> {
+    let index = ["?t=", "&t=", "#t="]
+        .iter()
+        .find_map(|key| Some(link.find(key)? + key.len()))?;
+    let value = link[index..].split(['&', '#']).next().unwrap_or_default();
+    let mut seconds = 0.0;
+    let mut number = String::new();
+    for c in value.chars() {
+        match c {
+            '0'..='9' | '.' => number.push(c),
+            'h' | 'm' | 's' => {
+                let unit = match c {
+                    'h' => 3600.0,
+                    'm' => 60.0,
+                    _ => 1.0,
+                };
+                seconds += number.parse::<f64>().ok()? * unit;
+                number.clear();
+            }
+            _ => return None,
+        }
+    }
+    if !number.is_empty() {
+        seconds += number.parse::<f64>().ok()?;
+    }
+    (!value.is_empty()).then(|| format_time(seconds))
+}
+
+// Accepts ss, mm:ss or hh:mm:ss where the seconds may carry a fraction, and
+// returns the canonical hh:mm:ss[.mmm] form ffmpeg is given. A percentage of
+// the video such as 10% or a time before its end such as -30 is kept relative
+// until the video length is known.
+pub fn handle_time(time: &str) -> Result<String, Error> {
+    if let Some(before_end) = time.trim().strip_prefix('-') {
+        if before_end.trim_end().ends_with('%') {
+            return Err(Error::InvalidTime(time.to_string()));
+        }
+        let before_end = handle_time(before_end).map_err(|e| match e {
+            Error::InvalidTimeField(_, reason) => Error::InvalidTimeField(time.to_string(), reason),
+            _ => Error::InvalidTime(time.to_string()),
+        })?;
+        return Ok(format!("-{}", before_end));
+    }
+    if let Some(percent) = time.trim().strip_suffix('%') {
+        return match percent.trim().parse::<f64>() {
+            Ok(percent) if (0.0..=100.0).contains(&percent) => Ok(format!("{}%", percent)),
+            _ => Err(Error::InvalidTimeField(
+                time.to_string(),
+                "percentages go from 0% to 100%".to_string(),
+            )),
+        };
+    }
+    let parts = time.trim().split(':').collect::<Vec<&str>>();
+    if parts.len() > 3 {
+        return Err(Error::InvalidTime(time.to_string()));
+    }
+    let field = |reason: String| Error::InvalidTimeField(time.to_string(), reason);
+    let last = parts.len() - 1;
+    for (i, part) in parts.iter().enumerate() {
+        let part = part.trim();
+        // Only the seconds may carry a fraction
+        let (whole, fraction) = match part.split_once('.') {
+            Some((whole, fraction)) if i == last => (whole, fraction),
+            _ => (part, "0"),
+        };
+        let digits = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+        if !digits(whole) || !digits(fraction) {
+            return Err(field(format!("\"{}\" is not a number", part)));
+        }
+    }
+    let units = ["seconds", "minutes"];
+    for (part, unit) in parts.iter().rev().zip(units).take(last) {
+        if part.trim().parse::<f64>().is_ok_and(|value| value >= 60.0) {
+            return Err(field(format!("{} {} is over 59", unit, part.trim())));
+        }
+    }
+    let seconds = time_to_seconds(time).ok_or_else(|| Error::InvalidTime(time.to_string()))?;
+    if seconds < 0.0 {
+        return Err(Error::InvalidTime(time.to_string()));
+    }
+    Ok(format_time(seconds))
+}
+
+pub fn format_time(seconds: f64) -> String {
+    let millis = (seconds * 1000.0).round() as u64;
+    let (seconds, millis) = (millis / 1000, millis % 1000);
+    let time = format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    );
+    match millis {
+        0 => time,
+        _ => format!("{}.{:03}", time, millis),
+    }
+}
+
+// Rounded to whole seconds for summaries, e.g. 1h 12m or 3m 05s
+pub fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.max(0.0).round() as u64;
+    match (seconds / 3600, seconds / 60 % 60, seconds % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m {:02}s", m, s),
+        (h, m, _) => format!("{}h {:02}m", h, m),
+    }
+}
+
+// None for percentages and times from the end, see resolve_times
+pub fn time_to_seconds(time: &str) -> Option<f64> {
+    if is_relative(time.trim()) {
+        return None;
+    }
+    let mut seconds = 0.0;
+    for part in time.split(':') {
+        seconds = seconds * 60.0 + part.trim().parse::<f64>().ok()?;
+    }
+    Some(seconds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shorts_links_become_watch_links() {
+        let watch = "https://www.youtube.com/watch?v=oKK4H33nUIs";
+        assert_eq!(
+            handle_link("https://www.youtube.com/shorts/oKK4H33nUIs"),
+            watch
+        );
+        assert_eq!(
+            handle_link("https://www.youtube.com/shorts/oKK4H33nUIs?si=LTe469e_gP5Co6yd"),
+            watch
+        );
+        let clip = VideoLink::new("https://youtube.com/shorts/oKK4H33nUIs?si=LTe469e_gP5Co6yd");
+        assert_eq!(clip.unwrap().id, "oKK4H33nUIs");
+        for link in [
+            "https://youtu.be/oKK4H33nUIs?si=LTe469e_gP5Co6yd",
+            "https://www.youtube.com/watch?v=oKK4H33nUIs&list=PLBCF2DAC6FFB574DE&index=2",
+            "https://www.youtube.com/live/oKK4H33nUIs?feature=share",
+        ] {
+            assert_eq!(handle_link(link), watch);
+        }
+    }
+
+    #[test]
+    fn links_without_a_video_id_are_rejected() {
+        for link in [
+            "",
+            "   ",
+            "https://vimeo.com/76979871",
+            "https://example.com/watch?v=abcdefghijk",
+            "https://www.youtube.com/watch?v=",
+            "https://www.youtube.com/channel/UC_x5XG1OV2P6uZZ5FSM9Ttw",
+            "https://www.youtube.com/channel/UC_x5XG1OV2P6uZZ5FSM9Ttw?si=LTe469e_gP5Co6yd",
+            "https://www.youtube.com/playlist?list=PLBCF2DAC6FFB574DE",
+            "https://vimeo.com/76979871?share=copy",
+            "https://example.com/page?x=1",
+            "https://youtu.be/abc?si=LTe469e_gP5Co6yd",
+            "https://www.youtube.com/watch?v=abcdefghijk!",
+        ] {
+            assert!(
+                matches!(VideoLink::new(link), Err(Error::InvalidLink(_))),
+                "{:?} was accepted",
+                link
+            );
+        }
+    }
+
+    #[test]
+    fn times_may_carry_a_fraction_of_a_second() {
+        assert_eq!(handle_time("1:02.25").unwrap(), "00:01:02.250");
+        assert_eq!(handle_time("90.5").unwrap(), "00:01:30.500");
+        assert_eq!(handle_time("45").unwrap(), "00:00:45");
+        assert_eq!(
+            time_to_seconds(&handle_time("1:02.25").unwrap()),
+            Some(62.25)
+        );
+    }
+
+    #[test]
+    fn invalid_time_fields_are_reported() {
+        match handle_time("ab:cd") {
+            Err(Error::InvalidTimeField(time, reason)) => {
+                assert_eq!(time, "ab:cd");
+                assert_eq!(reason, "\"ab\" is not a number");
+            }
+            other => panic!("ab:cd gave {:?}", other),
+        }
+        match handle_time("99:99") {
+            Err(Error::InvalidTimeField(_, reason)) => assert_eq!(reason, "seconds 99 is over 59"),
+            other => panic!("99:99 gave {:?}", other),
+        }
+        assert!(matches!(handle_time("1:2:3:4"), Err(Error::InvalidTime(_))));
+        assert_eq!(handle_time("1:59:59").unwrap(), "01:59:59");
+        // The leading field is not capped, 99 minutes is a valid time
+        assert_eq!(handle_time("99:59").unwrap(), "01:39:59");
+    }
+
+    fn clip(start: &str, end: &str) -> VideoLink {
+        let mut clip = VideoLink::new("https://youtu.be/abcdefghijk").unwrap();
+        clip.set_start_time(start).unwrap();
+        clip.set_end_time(end).unwrap();
+        clip
+    }
+
+    #[test]
+    fn percentages_resolve_against_the_duration() {
+        let mut percent = clip("10%", "25.5%");
+        assert!(percent.is_relative());
+        assert_eq!(percent.duration(), None);
+        percent.resolve_times(Some(200.0)).unwrap();
+        assert_eq!(percent.start_time, "00:00:20");
+        assert_eq!(percent.end_time.as_deref(), Some("00:00:51"));
+
+        let mut mixed = clip("0:05", "50%");
+        mixed.resolve_times(Some(60.0)).unwrap();
+        assert_eq!(mixed.duration(), Some(25.0));
+
+        assert!(clip("50%", "10%").resolve_times(Some(60.0)).is_err());
+        assert!(clip("10%", "end").resolve_times(None).is_err());
+        assert!(handle_time("100.5%").is_err());
+        assert!(handle_time("x%").is_err());
+    }
+
+    #[test]
+    fn negative_times_count_back_from_the_end() {
+        let mut outro = clip("-30", "end");
+        outro.resolve_times(Some(120.0)).unwrap();
+        assert_eq!(outro.start_time, "00:01:30");
+        assert_eq!(outro.end_time, None);
+
+        let mut trimmed = clip("0:05", "-10");
+        trimmed.resolve_times(Some(120.0)).unwrap();
+        assert_eq!(trimmed.end_time.as_deref(), Some("00:01:50"));
+
+        // Further back than the video is long clamps to its start
+        let mut long = clip("-5:00", "-0:10.5");
+        long.resolve_times(Some(120.0)).unwrap();
+        assert_eq!(long.start_time, "00:00:00");
+        assert_eq!(long.end_time.as_deref(), Some("00:01:49.500"));
+
+        assert!(clip("-1:00", "-2:00").resolve_times(Some(120.0)).is_err());
+        assert!(handle_time("-10%").is_err());
+
+        // Unresolved they are no time at all
+        assert_eq!(time_to_seconds("-00:00:30"), None);
+        assert_eq!(time_to_seconds("50%"), None);
+        assert!(!clip("-0", "end").is_whole());
+        match handle_time("-1:75") {
+            Err(Error::InvalidTimeField(time, _)) => assert_eq!(time, "-1:75"),
+            other => panic!("-1:75 gave {:?}", other),
+        }
+    }
+}